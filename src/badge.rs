@@ -1,10 +1,18 @@
 extern crate base64;
 extern crate chrono;
+extern crate regex;
 extern crate rusttype;
+extern crate tracing;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use base64::display::Base64Display;
+use cached::once_cell::sync::Lazy;
 use chrono::Duration;
+use regex::Regex;
 use rusttype::{point, Font, FontCollection, Point, PositionedGlyph, Scale};
+use tracing::instrument;
 
 const FONT_DATA: &[u8] = include_bytes!("DejaVuSans.ttf");
 const FONT_SIZE: f32 = 11.;
@@ -107,12 +115,15 @@ impl Badge {
         }
         let seconds = d.num_seconds();
         if seconds != 1 {
-            return format!("{} seconds", seconds);
+            format!("{} seconds", seconds)
         } else {
-            return format!("{} second", seconds);
+            format!("{} second", seconds)
         }
     }
 
+    /// Renders the badge, escaping the text so that it cannot add markup to the image and
+    /// replacing a color that is not a CSS color with `COLOR_OTHER`
+    #[instrument(name = "badge.to_svg", skip_all)]
     pub fn to_svg(&self) -> String {
         let duration = match &self.options.duration {
             Some(v) => Self::human_str(v),
@@ -121,6 +132,13 @@ impl Badge {
         let left_width = self.calculate_width(&self.options.status) + 6;
         let right_width = self.calculate_width(&duration) + 6;
         let offset = if self.options.muted { 20 } else { 0 };
+        let status = escape_xml(&self.options.status);
+        let duration = escape_xml(&duration);
+        let color = if is_css_color(&self.options.color) {
+            &self.options.color
+        } else {
+            COLOR_OTHER
+        };
 
         let svg = format!(
             r###"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="{}" height="{}">
@@ -153,15 +171,15 @@ impl Badge {
             self.options.height.unwrap_or(20),
             offset + left_width + right_width,
             offset + left_width,
-            self.options.color,
+            color,
             offset + left_width,
             right_width,
             offset + left_width + right_width,
             if self.options.muted { MUTE } else { "" },
             offset + (left_width) / 2,
-            &self.options.status,
+            &status,
             offset + (left_width) / 2,
-            &self.options.status,
+            &status,
             offset + left_width + (right_width / 2),
             &duration,
             offset + left_width + (right_width / 2),
@@ -184,13 +202,232 @@ impl Badge {
             .next()
             .unwrap_or(0.0)
             .ceil() as u32;
-        width + ((text.len() as u32 - 1) * 2)
+        width + (text.len() as u32).saturating_sub(1) * 2
+    }
+}
+
+/// Escapes text for XML or HTML content or a quoted attribute value, so that parsers read it back
+/// unchanged, dropping the control characters that XML does not allow even when escaped
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The named colors of CSS, sorted
+const CSS_COLOR_NAMES: &[&str] = &[
+    "aliceblue",
+    "antiquewhite",
+    "aqua",
+    "aquamarine",
+    "azure",
+    "beige",
+    "bisque",
+    "black",
+    "blanchedalmond",
+    "blue",
+    "blueviolet",
+    "brown",
+    "burlywood",
+    "cadetblue",
+    "chartreuse",
+    "chocolate",
+    "coral",
+    "cornflowerblue",
+    "cornsilk",
+    "crimson",
+    "cyan",
+    "darkblue",
+    "darkcyan",
+    "darkgoldenrod",
+    "darkgray",
+    "darkgreen",
+    "darkgrey",
+    "darkkhaki",
+    "darkmagenta",
+    "darkolivegreen",
+    "darkorange",
+    "darkorchid",
+    "darkred",
+    "darksalmon",
+    "darkseagreen",
+    "darkslateblue",
+    "darkslategray",
+    "darkslategrey",
+    "darkturquoise",
+    "darkviolet",
+    "deeppink",
+    "deepskyblue",
+    "dimgray",
+    "dimgrey",
+    "dodgerblue",
+    "firebrick",
+    "floralwhite",
+    "forestgreen",
+    "fuchsia",
+    "gainsboro",
+    "ghostwhite",
+    "gold",
+    "goldenrod",
+    "gray",
+    "green",
+    "greenyellow",
+    "grey",
+    "honeydew",
+    "hotpink",
+    "indianred",
+    "indigo",
+    "ivory",
+    "khaki",
+    "lavender",
+    "lavenderblush",
+    "lawngreen",
+    "lemonchiffon",
+    "lightblue",
+    "lightcoral",
+    "lightcyan",
+    "lightgoldenrodyellow",
+    "lightgray",
+    "lightgreen",
+    "lightgrey",
+    "lightpink",
+    "lightsalmon",
+    "lightseagreen",
+    "lightskyblue",
+    "lightslategray",
+    "lightslategrey",
+    "lightsteelblue",
+    "lightyellow",
+    "lime",
+    "limegreen",
+    "linen",
+    "magenta",
+    "maroon",
+    "mediumaquamarine",
+    "mediumblue",
+    "mediumorchid",
+    "mediumpurple",
+    "mediumseagreen",
+    "mediumslateblue",
+    "mediumspringgreen",
+    "mediumturquoise",
+    "mediumvioletred",
+    "midnightblue",
+    "mintcream",
+    "mistyrose",
+    "moccasin",
+    "navajowhite",
+    "navy",
+    "oldlace",
+    "olive",
+    "olivedrab",
+    "orange",
+    "orangered",
+    "orchid",
+    "palegoldenrod",
+    "palegreen",
+    "paleturquoise",
+    "palevioletred",
+    "papayawhip",
+    "peachpuff",
+    "peru",
+    "pink",
+    "plum",
+    "powderblue",
+    "purple",
+    "rebeccapurple",
+    "red",
+    "rosybrown",
+    "royalblue",
+    "saddlebrown",
+    "salmon",
+    "sandybrown",
+    "seagreen",
+    "seashell",
+    "sienna",
+    "silver",
+    "skyblue",
+    "slateblue",
+    "slategray",
+    "slategrey",
+    "snow",
+    "springgreen",
+    "steelblue",
+    "tan",
+    "teal",
+    "thistle",
+    "tomato",
+    "turquoise",
+    "violet",
+    "wheat",
+    "white",
+    "whitesmoke",
+    "yellow",
+    "yellowgreen",
+];
+
+/// `rgb()` and `rgba()` colors with comma separated numbers or percentages
+static CSS_RGB: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?i)rgba?\(\s*(\d{1,3}%?\s*,\s*){2}\d{1,3}%?\s*(,\s*(\d*\.)?\d+%?\s*)?\)$")
+        .unwrap()
+});
+
+/// Whether the color is a CSS hex color such as `#4c1` or `#41c464cc`, an `rgb()` or `rgba()`
+/// color, or a named color such as `teal`
+pub fn is_css_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => {
+            [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => {
+            CSS_RGB.is_match(color)
+                || CSS_COLOR_NAMES
+                    .binary_search(&color.to_ascii_lowercase().as_str())
+                    .is_ok()
+        }
     }
 }
 
+/// A strong entity tag for a rendered badge, which changes whenever the image does
+///
+/// The hash is only stable for a given build, which is enough for replicas of the same release.
+pub fn etag(svg: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    svg.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Whether an `If-None-Match` header matches the entity tag, using the weak comparison
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use chrono::Duration;
+    use proptest::prelude::*;
+
+    use crate::badge::{
+        escape_xml, etag, etag_matches, is_css_color, Badge, BadgeOptions, COLOR_DANGER,
+        COLOR_OTHER, COLOR_SUCCESS, COLOR_WARNING, CSS_COLOR_NAMES,
+    };
+    use crate::datadog::DatadogError;
 
     fn options() -> BadgeOptions {
         BadgeOptions::default()
@@ -204,19 +441,261 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
-    fn test_to_svg() {
-        use std::fs::File;
-        use std::io::Write;
-        let mut file = File::create("test.svg").unwrap();
-        let options = BadgeOptions {
-            duration: Some(Duration::hours(16)),
-            status: "ALERT".to_owned(),
-            muted: true,
-            color: COLOR_WARNING.to_string(),
-            ..BadgeOptions::default()
-        };
-        let badge = Badge::new(options);
-        file.write_all(badge.to_svg().as_bytes()).unwrap();
+    fn test_etag() {
+        let svg = Badge::new(options()).to_svg();
+        let tag = etag(&svg);
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_eq!(tag, etag(&svg));
+        assert_ne!(tag, etag(&svg.replace("Ok", "Alert")));
+        assert!(etag_matches(&tag, &tag));
+        assert!(etag_matches(&format!("\"other\", W/{}", tag), &tag));
+        assert!(etag_matches("*", &tag));
+        assert!(!etag_matches("\"other\"", &tag));
+    }
+
+    /// The text of each `text` element of the badge, which must be well-formed XML
+    fn texts(svg: &str) -> Vec<String> {
+        let document = roxmltree::Document::parse(svg).unwrap();
+        document
+            .descendants()
+            .filter(|node| node.has_tag_name("text"))
+            .map(|node| node.text().unwrap_or("").to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_to_svg_escapes() {
+        let status = "Unconfigured account: a</text><script>alert('x' & \"y\")</script>";
+        let svg = Badge::new(BadgeOptions {
+            status: status.to_owned(),
+            color: "red\"/><script>alert(1)</script><rect fill=\"red".to_owned(),
+            ..options()
+        })
+        .to_svg();
+        assert!(!svg.contains("<script"));
+        assert!(svg.contains(&format!("fill=\"{}\"", COLOR_OTHER)));
+        assert_eq!(texts(&svg), vec![status, status, "n/a", "n/a"]);
+    }
+
+    #[test]
+    fn test_escape_xml() {
+        assert_eq!(
+            escape_xml(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(escape_xml("a\tb\u{1}c\n"), "a&#9;bc&#10;");
+    }
+
+    #[test]
+    fn test_is_css_color() {
+        for color in &[
+            "#4c1",
+            "#4c1a",
+            "#41c464",
+            "#41C464cc",
+            "teal",
+            "RebeccaPurple",
+            "rgb(65, 196, 100)",
+            "RGBA(65,196,100,.5)",
+            "rgba(100%, 0%, 0%, 50%)",
+        ] {
+            assert!(is_css_color(color), "{}", color);
+        }
+        for color in &[
+            "",
+            "#",
+            "#12",
+            "#12345",
+            "#ggg",
+            "4c1",
+            "red;",
+            "url(#x)",
+            "r\"ed",
+            "notacolor",
+            "Running",
+            "rgb(1, 2)",
+            "rgb(1, 2, 3",
+            "rgb(1, 2, 3) red",
+        ] {
+            assert!(!is_css_color(color), "{}", color);
+        }
+        assert!(CSS_COLOR_NAMES.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_to_svg_well_formed(
+            status in "\\PC*|.*",
+            color in ".*",
+            muted: bool,
+            seconds in proptest::option::of(-1_000_000_000i64..1_000_000_000),
+        ) {
+            let svg = Badge::new(BadgeOptions {
+                status: status.clone(),
+                duration: seconds.map(Duration::seconds),
+                color,
+                muted,
+                ..options()
+            })
+            .to_svg();
+            let expected: String = status
+                .chars()
+                .filter(|c| !escape_xml(&c.to_string()).is_empty())
+                .collect();
+            let texts = texts(&svg);
+            prop_assert_eq!(texts.len(), 4);
+            prop_assert_eq!(&texts[0], &expected);
+            prop_assert_eq!(&texts[1], &expected);
+        }
+
+        #[test]
+        fn prop_escape_xml_has_no_markup(text in ".*") {
+            let escaped = escape_xml(&text);
+            prop_assert!(!escaped.contains(['<', '>', '"', '\'']));
+            let entities = escaped.split('&').skip(1).all(|entity| {
+                ["amp;", "lt;", "gt;", "quot;", "#39;", "#9;", "#10;", "#13;"]
+                    .iter()
+                    .any(|name| entity.starts_with(name))
+            });
+            prop_assert!(entities, "unknown entity in {}", escaped);
+        }
+    }
+
+    /// The statuses a badge can show, with the colour the service gives each of them
+    const STATUSES: &[(&str, &str)] = &[
+        ("Ok", COLOR_SUCCESS),
+        ("Skipped", COLOR_SUCCESS),
+        ("Warn", COLOR_WARNING),
+        ("Alert", COLOR_DANGER),
+        ("Unknown", COLOR_DANGER),
+        ("No Data", COLOR_OTHER),
+        ("Ignored", COLOR_OTHER),
+    ];
+
+    /// One error of each kind, whose badges have neither a duration nor a mute
+    fn errors() -> Vec<DatadogError> {
+        vec![
+            DatadogError::Config {
+                account: "example-team".to_owned(),
+            },
+            DatadogError::Network("connection refused".to_owned()),
+            DatadogError::Timeout,
+            DatadogError::Auth(403),
+            DatadogError::NotFound,
+            DatadogError::RateLimited { retry_after: None },
+            DatadogError::Upstream(500),
+            DatadogError::Decode("expected value".to_owned()),
+        ]
+    }
+
+    /// Every combination of status, duration and mute, plus each status and error at a fixed
+    /// size, named after its golden file
+    fn golden_badges() -> Vec<(String, BadgeOptions)> {
+        let durations = [
+            ("none", None),
+            ("1s", Some(Duration::seconds(1))),
+            ("16h", Some(Duration::hours(16))),
+            ("3w", Some(Duration::weeks(3))),
+        ];
+        let mut badges = Vec::new();
+        for (status, color) in STATUSES {
+            let status_name = status.to_lowercase().replace(' ', "-");
+            let options = BadgeOptions {
+                status: (*status).to_owned(),
+                color: (*color).to_owned(),
+                ..BadgeOptions::default()
+            };
+            for (duration_name, duration) in &durations {
+                for &muted in &[false, true] {
+                    let name = format!(
+                        "{}-{}{}.svg",
+                        status_name,
+                        duration_name,
+                        if muted { "-muted" } else { "" }
+                    );
+                    let options = BadgeOptions {
+                        duration: *duration,
+                        muted,
+                        ..options.clone()
+                    };
+                    badges.push((name, options));
+                }
+            }
+            let options = BadgeOptions {
+                width: Some(200),
+                height: Some(40),
+                ..options
+            };
+            badges.push((format!("{}-200x40.svg", status_name), options));
+        }
+        for error in errors() {
+            let text = error.badge_text();
+            let error_name: String = text
+                .to_lowercase()
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join("-");
+            let options = BadgeOptions {
+                status: text,
+                color: error.badge_color().to_owned(),
+                ..BadgeOptions::default()
+            };
+            badges.push((format!("error-{}.svg", error_name), options.clone()));
+            let options = BadgeOptions {
+                width: Some(200),
+                height: Some(40),
+                ..options
+            };
+            badges.push((format!("error-{}-200x40.svg", error_name), options));
+        }
+        badges
+    }
+
+    /// Compares every badge with its golden file in `src/test_data/badges`; run with
+    /// `UPDATE_GOLDEN=1` to write the files instead, then review the changed images
+    #[test]
+    fn test_golden_svgs() {
+        use std::collections::HashSet;
+        use std::fs;
+        use std::path::Path;
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_data/badges");
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+        if update {
+            fs::create_dir_all(&dir).unwrap();
+        }
+        let badges = golden_badges();
+        let mut failures = Vec::new();
+        for (name, options) in &badges {
+            let svg = Badge::new(options.clone()).to_svg();
+            let path = dir.join(name);
+            if update {
+                fs::write(&path, &svg).unwrap();
+            } else {
+                match fs::read_to_string(&path) {
+                    Ok(golden) if golden == svg => {}
+                    Ok(_) => failures.push(format!("{} differs", name)),
+                    Err(e) => failures.push(format!("{}: {}", name, e)),
+                }
+            }
+        }
+        let names: HashSet<&str> = badges.iter().map(|(name, _)| name.as_str()).collect();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+            if !names.contains(name.as_str()) {
+                if update {
+                    fs::remove_file(&path).unwrap();
+                } else {
+                    failures.push(format!("{} is not rendered by any test", name));
+                }
+            }
+        }
+        assert!(
+            failures.is_empty(),
+            "golden badges do not match, rerun with UPDATE_GOLDEN=1 if the change is intended:\n{}",
+            failures.join("\n")
+        );
     }
 }
@@ -0,0 +1,470 @@
+extern crate cached;
+extern crate chrono;
+extern crate redis;
+extern crate serde;
+extern crate serde_json;
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use cached::{Cached, TimedCache};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::{self, BoxFuture, FutureExt};
+use log::warn;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, IntoConnectionInfo, RedisResult};
+use serde::{Deserialize, Serialize};
+
+use crate::datadog::{DatadogError, MonitorState};
+use crate::persist::PersistedMonitor;
+
+/// Identifies a monitor fetch by its account, monitor ID and whether the group states were requested
+pub type MonitorKey = (String, String, bool);
+
+/// The outcome of fetching a monitor from Datadog
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Fetched {
+    /// The state of the monitor
+    Monitor(MonitorState),
+    /// Why Datadog could not provide the state of the monitor
+    Failed(DatadogError),
+}
+
+/// A fetched monitor, in a form that can be shared between processes
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct CachedMonitor {
+    pub result: Fetched,
+    /// When the monitor was fetched from Datadog, as seconds since the epoch
+    pub fetched: i64,
+}
+
+impl CachedMonitor {
+    /// Captures a monitor that has just been fetched
+    pub fn new(result: Fetched) -> Self {
+        CachedMonitor {
+            result,
+            fetched: Utc::now().timestamp(),
+        }
+    }
+
+    pub fn fetched(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.fetched, 0)
+    }
+
+    /// The monitors that an SLO or tag query is made of
+    pub fn monitor_ids(&self) -> &[u64] {
+        match &self.result {
+            Fetched::Monitor(state) => &state.monitor_ids,
+            Fetched::Failed(_) => &[],
+        }
+    }
+}
+
+/// Somewhere to keep fetched monitors for a limited time
+pub trait MonitorCache: Send + Sync {
+    fn get<'a>(&'a self, key: &'a MonitorKey) -> BoxFuture<'a, Option<CachedMonitor>>;
+
+    fn set<'a>(&'a self, key: MonitorKey, monitor: CachedMonitor) -> BoxFuture<'a, ()>;
+
+    /// Evicts the monitor along with any SLO or tag query made of it, returning the number of
+    /// cache entries evicted
+    fn evict<'a>(&'a self, account: &'a str, id: &'a str) -> BoxFuture<'a, usize>;
+
+    /// Stops fetching the monitors of the account for a number of seconds, as Datadog is rate
+    /// limiting it
+    fn back_off<'a>(&'a self, account: &'a str, seconds: u64) -> BoxFuture<'a, ()>;
+
+    /// How many more seconds to wait before fetching the monitors of the account, if any
+    fn backing_off<'a>(&'a self, account: &'a str) -> BoxFuture<'a, Option<u64>>;
+}
+
+/// The seconds left until the time, if it has not passed yet
+fn seconds_until(until: i64) -> Option<u64> {
+    let left = until - Utc::now().timestamp();
+    if left > 0 {
+        Some(left as u64)
+    } else {
+        None
+    }
+}
+
+fn monitor_keys(account: &str, id: &str) -> [MonitorKey; 2] {
+    [
+        (account.to_owned(), id.to_owned(), false),
+        (account.to_owned(), id.to_owned(), true),
+    ]
+}
+
+struct MemoryCacheState {
+    monitors: TimedCache<MonitorKey, CachedMonitor>,
+    /// The last fetch of each monitor, only tracked when the cache is persisted to disk
+    persisted: Option<HashMap<MonitorKey, CachedMonitor>>,
+    /// Whether any monitor has been fetched since the cache was last persisted
+    dirty: bool,
+    /// When each rate limited account can be fetched again, as seconds since the epoch
+    backoffs: HashMap<String, i64>,
+    /// The SLOs and tag queries made of each monitor, by account and monitor ID
+    includes: HashMap<(String, String), HashSet<MonitorKey>>,
+}
+
+impl MemoryCacheState {
+    fn insert(&mut self, key: MonitorKey, monitor: CachedMonitor) {
+        for id in monitor.monitor_ids() {
+            self.includes
+                .entry((key.0.clone(), id.to_string()))
+                .or_default()
+                .insert(key.clone());
+        }
+        self.monitors.cache_set(key, monitor);
+    }
+}
+
+/// A cache local to this process
+pub struct MemoryCache {
+    state: Mutex<MemoryCacheState>,
+}
+
+impl MemoryCache {
+    pub fn with_lifespan(seconds: u64) -> MemoryCache {
+        MemoryCache {
+            state: Mutex::new(MemoryCacheState {
+                monitors: TimedCache::with_lifespan(seconds),
+                persisted: None,
+                dirty: false,
+                backoffs: HashMap::new(),
+                includes: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Starts tracking monitors for persistence, seeding the cache with the monitors that were
+    /// fetched within the last `max_age` seconds and returning how many were restored
+    pub fn restore(&self, monitors: Vec<PersistedMonitor>, max_age: i64) -> usize {
+        let now = Utc::now();
+        let mut state = self.state.lock().unwrap();
+        let mut persisted = HashMap::new();
+        for monitor in monitors {
+            if now
+                .signed_duration_since(monitor.monitor.fetched())
+                .num_seconds()
+                <= max_age
+            {
+                let key = (monitor.account, monitor.id, monitor.with_groups);
+                state.insert(key.clone(), monitor.monitor.clone());
+                persisted.insert(key, monitor.monitor);
+            }
+        }
+        let restored = persisted.len();
+        state.persisted = Some(persisted);
+        restored
+    }
+
+    /// The monitors to persist, if any have changed since the last call
+    pub fn snapshot(&self, max_age: i64) -> Option<Vec<PersistedMonitor>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.dirty {
+            return None;
+        }
+        state.dirty = false;
+        let now = Utc::now();
+        state.persisted.as_mut().map(|persisted| {
+            persisted
+                .retain(|_, m| now.signed_duration_since(m.fetched()).num_seconds() <= max_age);
+            persisted
+                .iter()
+                .map(|((account, id, with_groups), monitor)| PersistedMonitor {
+                    account: account.clone(),
+                    id: id.clone(),
+                    with_groups: *with_groups,
+                    monitor: monitor.clone(),
+                })
+                .collect()
+        })
+    }
+}
+
+impl MonitorCache for MemoryCache {
+    fn get<'a>(&'a self, key: &'a MonitorKey) -> BoxFuture<'a, Option<CachedMonitor>> {
+        let mut state = self.state.lock().unwrap();
+        future::ready(state.monitors.cache_get(key).cloned()).boxed()
+    }
+
+    fn set<'a>(&'a self, key: MonitorKey, monitor: CachedMonitor) -> BoxFuture<'a, ()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(persisted) = &mut state.persisted {
+            persisted.insert(key.clone(), monitor.clone());
+            state.dirty = true;
+        }
+        state.insert(key, monitor);
+        future::ready(()).boxed()
+    }
+
+    fn evict<'a>(&'a self, account: &'a str, id: &'a str) -> BoxFuture<'a, usize> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let includes = state
+            .includes
+            .remove(&(account.to_owned(), id.to_owned()))
+            .unwrap_or_default();
+        let evicted = monitor_keys(account, id)
+            .iter()
+            .chain(includes.iter())
+            .filter_map(|key| {
+                if let Some(persisted) = &mut state.persisted {
+                    persisted.remove(key);
+                    state.dirty = true;
+                }
+                state.monitors.cache_remove(key)
+            })
+            .count();
+        future::ready(evicted).boxed()
+    }
+
+    fn back_off<'a>(&'a self, account: &'a str, seconds: u64) -> BoxFuture<'a, ()> {
+        let mut state = self.state.lock().unwrap();
+        let until = Utc::now().timestamp() + seconds as i64;
+        state
+            .backoffs
+            .retain(|_, until| seconds_until(*until).is_some());
+        state.backoffs.insert(account.to_owned(), until);
+        future::ready(()).boxed()
+    }
+
+    fn backing_off<'a>(&'a self, account: &'a str) -> BoxFuture<'a, Option<u64>> {
+        let state = self.state.lock().unwrap();
+        let left = state
+            .backoffs
+            .get(account)
+            .and_then(|until| seconds_until(*until));
+        future::ready(left).boxed()
+    }
+}
+
+/// A cache shared by every replica connected to the same Redis server
+pub struct RedisCache {
+    connection: ConnectionManager,
+    lifespan: usize,
+}
+
+impl RedisCache {
+    pub async fn connect(url: &str, lifespan: u64) -> RedisResult<RedisCache> {
+        let connection = ConnectionManager::new(url.into_connection_info()?).await?;
+        Ok(RedisCache {
+            connection,
+            lifespan: lifespan as usize,
+        })
+    }
+
+    fn backoff_key(account: &str) -> String {
+        format!(
+            "datadog-badges:backoff:{}",
+            serde_json::to_string(account).unwrap_or_default()
+        )
+    }
+
+    fn includes_key(account: &str, id: &str) -> String {
+        format!(
+            "datadog-badges:includes:{}",
+            serde_json::to_string(&(account, id)).unwrap_or_default()
+        )
+    }
+
+    fn redis_key(key: &MonitorKey) -> String {
+        // JSON keeps the key unambiguous whatever characters the account and ID contain
+        format!(
+            "datadog-badges:monitor:{}",
+            serde_json::to_string(key).unwrap_or_default()
+        )
+    }
+}
+
+impl MonitorCache for RedisCache {
+    fn get<'a>(&'a self, key: &'a MonitorKey) -> BoxFuture<'a, Option<CachedMonitor>> {
+        async move {
+            let mut connection = self.connection.clone();
+            let value: RedisResult<Option<String>> =
+                connection.get(RedisCache::redis_key(key)).await;
+            match value {
+                Ok(Some(value)) => serde_json::from_str(&value).ok(),
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("Could not read monitor from Redis: {}", e);
+                    None
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn set<'a>(&'a self, key: MonitorKey, monitor: CachedMonitor) -> BoxFuture<'a, ()> {
+        async move {
+            let value = match serde_json::to_string(&monitor) {
+                Ok(value) => value,
+                Err(_) => return,
+            };
+            let redis_key = RedisCache::redis_key(&key);
+            let mut pipe = redis::pipe();
+            pipe.set_ex(&redis_key, value, self.lifespan).ignore();
+            // each monitor of an SLO or tag query lists its key, for as long as it may be cached
+            for id in monitor.monitor_ids() {
+                let includes_key = RedisCache::includes_key(&key.0, &id.to_string());
+                pipe.sadd(&includes_key, &redis_key)
+                    .ignore()
+                    .expire(&includes_key, self.lifespan)
+                    .ignore();
+            }
+            let mut connection = self.connection.clone();
+            let result: RedisResult<()> = pipe.query_async(&mut connection).await;
+            if let Err(e) = result {
+                warn!("Could not write monitor to Redis: {}", e);
+            }
+        }
+        .boxed()
+    }
+
+    fn evict<'a>(&'a self, account: &'a str, id: &'a str) -> BoxFuture<'a, usize> {
+        async move {
+            let includes_key = RedisCache::includes_key(account, id);
+            let mut connection = self.connection.clone();
+            let result: RedisResult<usize> = async {
+                let mut keys: Vec<String> = connection.smembers(&includes_key).await?;
+                keys.extend(monitor_keys(account, id).iter().map(RedisCache::redis_key));
+                let (evicted, _): (usize, usize) = redis::pipe()
+                    .del(keys)
+                    .del(&includes_key)
+                    .query_async(&mut connection)
+                    .await?;
+                Ok(evicted)
+            }
+            .await;
+            result.unwrap_or_else(|e| {
+                warn!("Could not evict monitor from Redis: {}", e);
+                0
+            })
+        }
+        .boxed()
+    }
+
+    fn back_off<'a>(&'a self, account: &'a str, seconds: u64) -> BoxFuture<'a, ()> {
+        async move {
+            let until = Utc::now().timestamp() + seconds as i64;
+            let mut connection = self.connection.clone();
+            // the key expires when the backoff ends, so replicas need not agree on the time
+            let result: RedisResult<()> = connection
+                .set_ex(
+                    RedisCache::backoff_key(account),
+                    until,
+                    max(seconds, 1) as usize,
+                )
+                .await;
+            if let Err(e) = result {
+                warn!("Could not write rate limit backoff to Redis: {}", e);
+            }
+        }
+        .boxed()
+    }
+
+    fn backing_off<'a>(&'a self, account: &'a str) -> BoxFuture<'a, Option<u64>> {
+        async move {
+            let mut connection = self.connection.clone();
+            let value: RedisResult<Option<i64>> =
+                connection.get(RedisCache::backoff_key(account)).await;
+            match value {
+                Ok(until) => until.and_then(seconds_until),
+                Err(e) => {
+                    warn!("Could not read rate limit backoff from Redis: {}", e);
+                    None
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::cache::{CachedMonitor, Fetched, MemoryCache, MonitorCache, RedisCache};
+    use crate::datadog::{DatadogError, MonitorState, MonitorStatus};
+
+    fn monitor(status: MonitorStatus) -> CachedMonitor {
+        CachedMonitor {
+            result: Fetched::Monitor(MonitorState {
+                overall_state: status,
+                ..MonitorState::default()
+            }),
+            fetched: 0,
+        }
+    }
+
+    async fn check_cache(cache: &dyn MonitorCache) {
+        let key1 = ("team".to_owned(), "1".to_owned(), false);
+        let key2 = ("team".to_owned(), "1".to_owned(), true);
+        let key3 = ("team".to_owned(), "2".to_owned(), false);
+        cache.evict("team", "1").await;
+        cache.evict("team", "2").await;
+        assert_eq!(cache.get(&key1).await, None);
+        cache.set(key1.clone(), monitor(MonitorStatus::Ok)).await;
+        cache.set(key2.clone(), monitor(MonitorStatus::Alert)).await;
+        let unreachable = CachedMonitor {
+            result: Fetched::Failed(DatadogError::Timeout),
+            fetched: 0,
+        };
+        cache.set(key3.clone(), unreachable.clone()).await;
+        assert_eq!(cache.get(&key1).await, Some(monitor(MonitorStatus::Ok)));
+        assert_eq!(cache.get(&key2).await, Some(monitor(MonitorStatus::Alert)));
+        assert_eq!(cache.evict("team", "1").await, 2);
+        assert_eq!(cache.get(&key1).await, None);
+        assert_eq!(cache.get(&key2).await, None);
+        assert_eq!(cache.get(&key3).await, Some(unreachable));
+        cache.evict("team", "2").await;
+
+        let query = ("team".to_owned(), "tags:service:checkout".to_owned(), false);
+        let slo = ("team".to_owned(), "slo:abc".to_owned(), false);
+        let other = (
+            "other".to_owned(),
+            "tags:service:checkout".to_owned(),
+            false,
+        );
+        let made_of = |ids: Vec<u64>| CachedMonitor {
+            result: Fetched::Monitor(MonitorState {
+                monitor_ids: ids,
+                ..MonitorState::default()
+            }),
+            fetched: 0,
+        };
+        cache.set(query.clone(), made_of(vec![3, 4])).await;
+        cache.set(slo.clone(), made_of(vec![4])).await;
+        cache.set(other.clone(), made_of(vec![3])).await;
+        assert_eq!(cache.evict("team", "3").await, 1);
+        assert_eq!(cache.get(&query).await, None);
+        assert_eq!(cache.get(&slo).await, Some(made_of(vec![4])));
+        assert_eq!(cache.get(&other).await, Some(made_of(vec![3])));
+        assert_eq!(cache.evict("team", "4").await, 1);
+        assert_eq!(cache.get(&slo).await, None);
+        assert_eq!(cache.evict("other", "3").await, 1);
+
+        assert_eq!(cache.backing_off("team").await, None);
+        cache.back_off("team", 30).await;
+        let left = cache.backing_off("team").await.unwrap();
+        assert!(left > 0 && left <= 30);
+        assert_eq!(cache.backing_off("other").await, None);
+        cache.back_off("team", 0).await;
+        assert_eq!(cache.backing_off("team").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_cache() {
+        check_cache(&MemoryCache::with_lifespan(60)).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_cache() {
+        // needs a local redis-server, or REDIS_URL pointing at a disposable one
+        let url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+        check_cache(&RedisCache::connect(&url, 60).await.unwrap()).await;
+    }
+}
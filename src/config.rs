@@ -0,0 +1,448 @@
+extern crate serde;
+extern crate toml;
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::datadog::Target;
+
+/// The server configuration, loaded from the TOML file given by `--config`
+#[derive(Clone, Deserialize, Debug, Default, PartialEq)]
+pub struct Config {
+    /// Named badges, keyed by the name used in the `/badges/{name}` URL
+    #[serde(default)]
+    pub aliases: BTreeMap<String, Alias>,
+    /// The status page served at the context root, if any
+    #[serde(default)]
+    pub status_page: Option<StatusPage>,
+    /// Overrides of how long fetched monitors are cached for
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// Serve over HTTPS rather than plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// The certificate to serve HTTPS with
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// The PEM file of the certificate chain
+    pub cert: PathBuf,
+    /// The PEM file of the private key
+    pub key: PathBuf,
+    /// The port to redirect plain HTTP requests to HTTPS from, if any
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+}
+
+/// The cache TTL overrides, the most specific of which applies to each monitor
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct CacheConfig {
+    /// The lowest `max_age` that a request can ask for, in seconds
+    #[serde(default = "default_min_max_age")]
+    pub min_max_age: u64,
+    /// The TTL of the monitors of each account, keyed by account
+    #[serde(default)]
+    pub accounts: BTreeMap<String, u64>,
+    /// The TTL of the monitors with a given tag, the first matching rule wins
+    #[serde(default)]
+    pub tags: Vec<TagTtl>,
+    /// The accounts whose badges tell browsers and proxies not to cache them at all
+    #[serde(default)]
+    pub no_cache_accounts: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            min_max_age: default_min_max_age(),
+            accounts: BTreeMap::new(),
+            tags: Vec::new(),
+            no_cache_accounts: Vec::new(),
+        }
+    }
+}
+
+fn default_min_max_age() -> u64 {
+    5
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct TagTtl {
+    /// The monitor tag, e.g. `critical` or `team:checkout`
+    pub tag: String,
+    /// The TTL in seconds
+    pub ttl: u64,
+}
+
+impl CacheConfig {
+    /// The TTL of a monitor, taken from the alias, then the first tag rule matching the
+    /// monitor's tags, then the account and finally the `default`
+    pub fn ttl(&self, default: u64, account: &str, alias_ttl: Option<u64>, tags: &[String]) -> u64 {
+        alias_ttl
+            .or_else(|| {
+                self.tags
+                    .iter()
+                    .find(|rule| tags.contains(&rule.tag))
+                    .map(|rule| rule.ttl)
+            })
+            .or_else(|| self.accounts.get(account).copied())
+            .unwrap_or(default)
+    }
+}
+
+/// A named badge that resolves to a fixed account, query and exactly one of a monitor, an SLO or
+/// a tag query
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct Alias {
+    /// The Datadog account (subdomain) that the monitor belongs to
+    pub account: String,
+    /// The monitor ID
+    #[serde(default)]
+    pub monitor: Option<String>,
+    /// The ID of a service level objective, whose badge shows the status over its first timeframe
+    #[serde(default)]
+    pub slo: Option<String>,
+    /// Comma separated monitor tags, whose badge shows the worst status of the tagged monitors
+    #[serde(default)]
+    pub tags: Option<String>,
+    /// The query parameters to use for the badge, e.g. `q` and `g`
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+    /// How long the monitor is cached for when fetched through this alias, in seconds
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Whether the badge tells browsers and proxies not to cache it at all
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+impl Alias {
+    /// What the badge shows the status of
+    pub fn target(&self) -> Target {
+        match (&self.monitor, &self.slo, &self.tags) {
+            (Some(monitor), _, _) => Target::Monitor(monitor.clone()),
+            (None, Some(slo), _) => Target::Slo(slo.clone()),
+            (None, None, Some(tags)) => Target::Tags(tags.clone()),
+            (None, None, None) => Target::Monitor(String::new()),
+        }
+    }
+
+    fn validate(&self, name: &str) -> Result<(), ConfigError> {
+        let targets = [&self.monitor, &self.slo, &self.tags]
+            .iter()
+            .filter(|target| target.is_some())
+            .count();
+        if targets == 1 {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(format!(
+                "{} must have exactly one of monitor, slo and tags",
+                name
+            )))
+        }
+    }
+}
+
+/// A page showing the badges of a set of monitors, grouped into sections
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct StatusPage {
+    /// The title of the page
+    #[serde(default = "default_status_page_title")]
+    pub title: String,
+    /// How often the page refreshes itself, in seconds
+    #[serde(default = "default_status_page_refresh")]
+    pub refresh: u64,
+    #[serde(default)]
+    pub sections: Vec<StatusPageSection>,
+}
+
+fn default_status_page_title() -> String {
+    "Status".to_owned()
+}
+
+fn default_status_page_refresh() -> u64 {
+    60
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct StatusPageSection {
+    pub title: String,
+    #[serde(default)]
+    pub badges: Vec<StatusPageBadge>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct StatusPageBadge {
+    /// The name displayed next to the badge
+    pub name: String,
+    #[serde(flatten)]
+    pub target: BadgeTarget,
+}
+
+/// Identifies a badge either by the name of an alias or by its account, monitor and query
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum BadgeTarget {
+    Alias { alias: String },
+    Monitor(Alias),
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "could not read configuration: {}", e),
+            ConfigError::Parse(e) => write!(f, "could not parse configuration: {}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, alias) in &self.aliases {
+            alias.validate(&format!("alias {}", name))?;
+        }
+        if let Some(page) = &self.status_page {
+            for badge in page.sections.iter().flat_map(|s| s.badges.iter()) {
+                match &badge.target {
+                    BadgeTarget::Alias { alias } if !self.aliases.contains_key(alias) => {
+                        return Err(ConfigError::Invalid(format!(
+                            "status page badge {} refers to an undefined alias",
+                            badge.name
+                        )));
+                    }
+                    BadgeTarget::Alias { .. } => {}
+                    BadgeTarget::Monitor(monitor) => {
+                        monitor.validate(&format!("status page badge {}", badge.name))?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The longest TTL that any monitor can have, which is how long the cache must keep monitors
+    pub fn longest_ttl(&self, default: u64) -> u64 {
+        self.cache
+            .accounts
+            .values()
+            .copied()
+            .chain(self.cache.tags.iter().map(|rule| rule.ttl))
+            .chain(self.aliases.values().filter_map(|alias| alias.ttl))
+            .chain(
+                self.status_page
+                    .iter()
+                    .flat_map(|page| &page.sections)
+                    .flat_map(|section| &section.badges)
+                    .filter_map(|badge| match &badge.target {
+                        BadgeTarget::Monitor(monitor) => monitor.ttl,
+                        BadgeTarget::Alias { .. } => None,
+                    }),
+            )
+            .fold(default, max)
+    }
+
+    /// Looks up the account, target and query that a badge target refers to
+    pub fn resolve<'a>(&'a self, target: &'a BadgeTarget) -> Option<&'a Alias> {
+        match target {
+            BadgeTarget::Alias { alias } => self.aliases.get(alias),
+            BadgeTarget::Monitor(monitor) => Some(monitor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{BadgeTarget, Config};
+    use crate::datadog::Target;
+
+    #[test]
+    fn test_parse_aliases() {
+        let config = Config::parse(
+            r#"
+[aliases.checkout-prod]
+account = "example-team"
+monitor = "12345"
+query = { q = "env:prod" }
+
+[aliases.checkout]
+account = "example-team"
+monitor = "12345"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.aliases.len(), 2);
+        let alias = &config.aliases["checkout-prod"];
+        assert_eq!(alias.account, "example-team");
+        assert_eq!(alias.target(), Target::Monitor("12345".to_owned()));
+        assert_eq!(alias.query.get("q").map(String::as_str), Some("env:prod"));
+        assert!(config.aliases["checkout"].query.is_empty());
+    }
+
+    #[test]
+    fn test_parse_alias_targets() {
+        let config = Config::parse(
+            r#"
+[aliases.checkout-slo]
+account = "example-team"
+slo = "abc123"
+
+[aliases.checkout-all]
+account = "example-team"
+tags = "team:checkout,env:prod"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.aliases["checkout-slo"].target(),
+            Target::Slo("abc123".to_owned())
+        );
+        assert_eq!(
+            config.aliases["checkout-all"].target(),
+            Target::Tags("team:checkout,env:prod".to_owned())
+        );
+        for targets in &[
+            "",
+            "monitor = \"12345\"\nslo = \"abc123\"",
+            "slo = \"abc123\"\ntags = \"team:checkout\"",
+        ] {
+            let text = format!(
+                "[aliases.checkout]\naccount = \"example-team\"\n{}\n",
+                targets
+            );
+            assert!(Config::parse(&text).is_err(), "{}", targets);
+        }
+    }
+
+    #[test]
+    fn test_parse_status_page() {
+        let config = Config::parse(
+            r#"
+[aliases.checkout-prod]
+account = "example-team"
+monitor = "12345"
+
+[status_page]
+title = "Example Team"
+
+[[status_page.sections]]
+title = "Checkout"
+badges = [
+    { name = "Production", alias = "checkout-prod" },
+    { name = "Staging", account = "example-team", monitor = "12345", query = { q = "env:staging" }, ttl = 900 },
+]
+"#,
+        )
+        .unwrap();
+        let page = config.status_page.as_ref().unwrap();
+        assert_eq!(page.title, "Example Team");
+        assert_eq!(page.refresh, 60);
+        let badges = &page.sections[0].badges;
+        assert_eq!(
+            badges[0].target,
+            BadgeTarget::Alias {
+                alias: "checkout-prod".to_owned()
+            }
+        );
+        let staging = config.resolve(&badges[1].target).unwrap();
+        assert_eq!(staging.target(), Target::Monitor("12345".to_owned()));
+        assert_eq!(
+            staging.query.get("q").map(String::as_str),
+            Some("env:staging")
+        );
+        assert_eq!(config.longest_ttl(15), 900);
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let config = Config::parse(
+            r#"
+[aliases.checkout-readme]
+account = "example-team"
+monitor = "12345"
+ttl = 600
+
+[cache]
+min_max_age = 2
+accounts = { example-team = 60 }
+no_cache_accounts = ["other-team"]
+
+[[cache.tags]]
+tag = "critical"
+ttl = 5
+"#,
+        )
+        .unwrap();
+        let cache = &config.cache;
+        assert_eq!(cache.min_max_age, 2);
+        assert_eq!(cache.no_cache_accounts, vec!["other-team".to_owned()]);
+        assert!(!config.aliases["checkout-readme"].no_cache);
+        let critical = vec!["env:prod".to_owned(), "critical".to_owned()];
+        assert_eq!(cache.ttl(15, "example-team", Some(600), &critical), 600);
+        assert_eq!(cache.ttl(15, "example-team", None, &critical), 5);
+        assert_eq!(cache.ttl(15, "example-team", None, &[]), 60);
+        assert_eq!(cache.ttl(15, "other-team", None, &[]), 15);
+        assert_eq!(config.longest_ttl(15), 600);
+        assert_eq!(Config::default().longest_ttl(15), 15);
+    }
+
+    #[test]
+    fn test_parse_tls() {
+        let config = Config::parse(
+            r#"
+[tls]
+cert = "/etc/tls/tls.crt"
+key = "/etc/tls/tls.key"
+redirect_port = 8080
+"#,
+        )
+        .unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert.to_str(), Some("/etc/tls/tls.crt"));
+        assert_eq!(tls.redirect_port, Some(8080));
+    }
+
+    #[test]
+    fn test_undefined_alias() {
+        assert!(Config::parse(
+            r#"
+[[status_page.sections]]
+title = "Checkout"
+badges = [{ name = "Production", alias = "checkout-prod" }]
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+}
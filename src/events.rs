@@ -0,0 +1,308 @@
+extern crate serde;
+
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::{self, Either};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::time;
+
+use crate::badge::{Badge, BadgeOptions};
+use crate::datadog::Target;
+use crate::service::{BadgeService, Freshness, MonitorBadge};
+
+/// The data of each server-sent event pushed to live badges
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BadgeEvent {
+    pub status: String,
+    pub color: String,
+    pub muted: bool,
+    /// The rendered badge as a data URI
+    pub svg: String,
+}
+
+/// Identifies a stream of badge events by everything that affects the rendered badge
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct StreamKey {
+    pub account: String,
+    pub target: Target,
+    pub query: BTreeMap<String, String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The `max_age` query parameter, already within the configured bounds
+    pub max_age: Option<u64>,
+    /// The TTL of the alias that the badge was requested through
+    pub alias_ttl: Option<u64>,
+}
+
+struct Poller {
+    subscribers: Vec<UnboundedSender<Arc<BadgeEvent>>>,
+    /// The last event sent, for subscribers that join later
+    latest: Option<Arc<BadgeEvent>>,
+    /// Wakes the poller when a subscriber leaves
+    left: UnboundedSender<()>,
+}
+
+type Pollers = Arc<Mutex<HashMap<StreamKey, Poller>>>;
+
+/// Polls each badge that has subscribers once, however many subscribers it has, and pushes the
+/// badge to them whenever it changes
+pub struct EventHub {
+    service: Arc<BadgeService>,
+    pollers: Pollers,
+    /// How many badges can be polled at once, so that clients cannot start any number of pollers
+    /// by varying the monitor or the query
+    max_pollers: usize,
+}
+
+/// The events of a badge for one subscriber, which unsubscribes when dropped
+pub struct Subscription {
+    events: UnboundedReceiver<Arc<BadgeEvent>>,
+    left: UnboundedSender<()>,
+}
+
+impl Stream for Subscription {
+    type Item = Arc<BadgeEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // close first so that the poller sees this subscriber has gone when it wakes
+        self.events.close();
+        let _ = self.left.unbounded_send(());
+    }
+}
+
+impl EventHub {
+    pub fn new(service: Arc<BadgeService>, max_pollers: usize) -> EventHub {
+        EventHub {
+            service,
+            pollers: Arc::default(),
+            max_pollers,
+        }
+    }
+
+    /// Subscribes to the badge, starting to poll it if nobody else is subscribed, from within a
+    /// runtime; there is no subscription if that would poll more than `max_pollers` badges
+    pub fn subscribe(&self, key: StreamKey) -> Option<Subscription> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut pollers = self.pollers.lock().unwrap();
+        let full = pollers.len() >= self.max_pollers;
+        let left = match pollers.get_mut(&key) {
+            Some(poller) => {
+                if let Some(latest) = &poller.latest {
+                    let _ = tx.unbounded_send(latest.clone());
+                }
+                poller.subscribers.push(tx);
+                poller.left.clone()
+            }
+            None if full => return None,
+            None => {
+                let (left, left_rx) = mpsc::unbounded();
+                let poller = Poller {
+                    subscribers: vec![tx],
+                    latest: None,
+                    left: left.clone(),
+                };
+                pollers.insert(key.clone(), poller);
+                tokio::spawn(poll(
+                    self.service.clone(),
+                    self.pollers.clone(),
+                    key,
+                    left_rx,
+                ));
+                left
+            }
+        };
+        Some(Subscription { events: rx, left })
+    }
+
+    /// How many badges are being polled
+    pub fn pollers(&self) -> usize {
+        self.pollers.lock().unwrap().len()
+    }
+}
+
+/// Forgets the subscribers that have left, stopping the poller if none are left
+fn prune(pollers: &Pollers, key: &StreamKey) -> bool {
+    let mut pollers = pollers.lock().unwrap();
+    let stopped = match pollers.get_mut(key) {
+        Some(poller) => {
+            poller.subscribers.retain(|s| !s.is_closed());
+            poller.subscribers.is_empty()
+        }
+        None => return true,
+    };
+    if stopped {
+        pollers.remove(key);
+    }
+    stopped
+}
+
+async fn poll(
+    service: Arc<BadgeService>,
+    pollers: Pollers,
+    key: StreamKey,
+    mut left: UnboundedReceiver<()>,
+) {
+    let freshness = Freshness {
+        alias_ttl: key.alias_ttl,
+        max_age: key.max_age,
+    };
+    let mut last_svg = String::new();
+    loop {
+        let MonitorBadge {
+            options, fresh_for, ..
+        } = service
+            .monitor_badge(&key.account, &key.target, &key.query, freshness)
+            .await;
+        let badge = Badge::new(BadgeOptions {
+            width: key.width,
+            height: key.height,
+            ..options.clone()
+        });
+        // the duration is part of the image so compare the rendered badge
+        let svg = badge.to_svg();
+        if svg != last_svg {
+            let event = Arc::new(BadgeEvent {
+                status: options.status,
+                color: options.color,
+                muted: options.muted,
+                svg: badge.to_svg_data_uri(),
+            });
+            if let Some(poller) = pollers.lock().unwrap().get_mut(&key) {
+                poller
+                    .subscribers
+                    .retain(|s| s.unbounded_send(event.clone()).is_ok());
+                poller.latest = Some(event);
+            }
+            last_svg = svg;
+        }
+        if prune(&pollers, &key) {
+            return;
+        }
+        // wait for the badge to go stale, stopping early once every subscriber has left
+        let mut delay = Box::pin(time::delay_for(Duration::from_secs(max(fresh_for, 1))));
+        loop {
+            match future::select(delay, left.next()).await {
+                Either::Left(_) => break,
+                Either::Right((_, rest)) => {
+                    if prune(&pollers, &key) {
+                        return;
+                    }
+                    delay = rest;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use futures::StreamExt;
+    use tokio::time;
+
+    use crate::cache::MemoryCache;
+    use crate::datadog::{MonitorState, MonitorStatus, Target};
+    use crate::events::{EventHub, StreamKey};
+    use crate::history::TransitionHistory;
+    use crate::service::{BadgeService, CacheTtls};
+    use crate::source::FakeSource;
+
+    fn key(id: &str) -> StreamKey {
+        StreamKey {
+            account: "team".to_owned(),
+            target: Target::Monitor(id.to_owned()),
+            query: BTreeMap::new(),
+            width: None,
+            height: None,
+            max_age: None,
+            alias_ttl: None,
+        }
+    }
+
+    fn hub(source: &Arc<FakeSource>) -> EventHub {
+        source.set(
+            "team",
+            "1",
+            Ok(MonitorState {
+                overall_state: MonitorStatus::Alert,
+                overall_state_modified: Some(Utc::now()),
+                ..MonitorState::default()
+            }),
+        );
+        let service = BadgeService::new(
+            source.clone(),
+            Arc::new(MemoryCache::with_lifespan(60)),
+            CacheTtls {
+                default: 60,
+                longest: 60,
+                ..CacheTtls::default()
+            },
+            TransitionHistory::new(10, 100),
+        );
+        EventHub::new(Arc::new(service), 2)
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_share_poller() {
+        let source = Arc::new(FakeSource::new());
+        let hub = hub(&source);
+        let mut first = hub.subscribe(key("1")).unwrap();
+        let event = first.next().await.unwrap();
+        assert_eq!(event.status, "Alert");
+        assert!(event.svg.starts_with("data:image/svg+xml;base64,"));
+        // a later subscriber is sent the current badge without another fetch
+        let mut second = hub.subscribe(key("1")).unwrap();
+        assert_eq!(second.next().await, Some(event));
+        assert_eq!(hub.pollers(), 1);
+        assert_eq!(source.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_poller_stops_when_unsubscribed() {
+        let source = Arc::new(FakeSource::new());
+        let hub = hub(&source);
+        let mut first = hub.subscribe(key("1")).unwrap();
+        let second = hub.subscribe(key("1")).unwrap();
+        first.next().await.unwrap();
+        drop(first);
+        time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(hub.pollers(), 1);
+        // the poller stops long before the badge goes stale
+        drop(second);
+        time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(hub.pollers(), 0);
+        assert_eq!(source.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pollers_limited() {
+        let source = Arc::new(FakeSource::new());
+        let hub = hub(&source);
+        let first = hub.subscribe(key("1")).unwrap();
+        let _second = hub.subscribe(key("2")).unwrap();
+        assert!(hub.subscribe(key("3")).is_none());
+        // badges that are already polled can still be subscribed to
+        assert!(hub.subscribe(key("1")).is_some());
+        drop(first);
+        time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(hub.pollers(), 1);
+        assert!(hub.subscribe(key("3")).is_some());
+    }
+}
@@ -0,0 +1,379 @@
+extern crate regex;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use cached::once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::datadog::{MonitorState, MonitorStatus};
+
+/// The index of the recorded monitors in a fixtures directory, `index.toml`
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct FixtureIndex {
+    #[serde(default)]
+    pub fixtures: Vec<Fixture>,
+}
+
+/// A recorded monitor and the statuses that its badges are expected to show
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Fixture {
+    /// The JSON file of the monitor, relative to the fixtures directory
+    pub file: String,
+    /// The type of the monitor, e.g. `metric alert` or `composite`
+    #[serde(rename = "type")]
+    pub monitor_type: String,
+    pub expect: Vec<Expectation>,
+}
+
+/// The status of the monitor for a filter
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Expectation {
+    /// The `q` query parameter, absent for the unfiltered badge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    pub status: MonitorStatus,
+    /// When the monitor entered the status, in RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+}
+
+impl FixtureIndex {
+    pub fn load(dir: &Path) -> io::Result<FixtureIndex> {
+        toml::from_str(&fs::read_to_string(dir.join("index.toml"))?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Replaces the values of tags with placeholders, the same value always with the same placeholder
+/// so that filters still match the same groups
+#[derive(Default)]
+struct Pseudonyms {
+    values: HashMap<(String, String), String>,
+    counts: HashMap<String, usize>,
+}
+
+impl Pseudonyms {
+    fn value(&mut self, key: &str, value: &str) -> String {
+        if value.is_empty() || value == "*" {
+            return value.to_owned();
+        }
+        let counts = &mut self.counts;
+        self.values
+            .entry((key.to_owned(), value.to_owned()))
+            .or_insert_with(|| {
+                let count = counts.entry(key.to_owned()).or_insert(0);
+                *count += 1;
+                format!("{}-{}", key, count)
+            })
+            .clone()
+    }
+
+    /// A `key:value` tag, or a simple tag without a value
+    fn tag(&mut self, tag: &str) -> String {
+        match tag.split_once(':') {
+            Some((key, value)) => format!("{}:{}", key, self.value(key, value)),
+            None => self.value("tag", tag),
+        }
+    }
+
+    /// A group name or downtime scope, which is a list of tags separated by commas
+    fn scope(&mut self, scope: &str) -> String {
+        scope
+            .split(',')
+            .map(|tag| self.tag(tag.trim()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// The tags within the braces and string literals of a monitor query
+    fn query(&mut self, query: &str) -> String {
+        static SEGMENT: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r#"\{[^}]*\}|"[^"]*"|'[^']*'"#).unwrap());
+        static TAG: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r#"\b([A-Za-z][\w.\-/]*):([^\s,(){}"']+)"#).unwrap());
+        SEGMENT
+            .replace_all(query, |segment: &Captures| {
+                TAG.replace_all(&segment[0], |tag: &Captures| {
+                    format!("{}:{}", &tag[1], self.value(&tag[1], &tag[2]))
+                })
+                .into_owned()
+            })
+            .into_owned()
+    }
+}
+
+/// Removes from a monitor as Datadog describes it everything that could identify the organisation
+/// that owns it: the name, message and creator are replaced, and the values of tags are replaced
+/// consistently across the tags, query, groups, silenced scopes and downtimes
+pub fn anonymise(monitor: &mut Value) {
+    let mut pseudonyms = Pseudonyms::default();
+    let monitor = match monitor.as_object_mut() {
+        Some(monitor) => monitor,
+        None => return,
+    };
+    let monitor_type = monitor
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or("monitor")
+        .to_owned();
+    for (name, value) in monitor.iter_mut() {
+        match (name.as_str(), value) {
+            ("name", value) => *value = Value::from(format!("Anonymised {}", monitor_type)),
+            ("message", value) => *value = Value::from(""),
+            ("org_id", value) => *value = Value::from(1),
+            ("creator", value @ Value::Object(_)) => {
+                *value = json!({
+                    "email": "someone@example.com",
+                    "handle": "someone@example.com",
+                    "id": 1,
+                    "name": "Someone",
+                })
+            }
+            ("restricted_roles", value @ Value::Array(_)) => *value = json!([]),
+            ("tags", Value::Array(tags)) => {
+                for tag in tags.iter_mut() {
+                    if let Some(t) = tag.as_str() {
+                        *tag = Value::from(pseudonyms.tag(t));
+                    }
+                }
+            }
+            ("query", Value::String(query)) => *query = pseudonyms.query(query),
+            ("options", Value::Object(options)) => {
+                if let Some(Value::Object(silenced)) = options.get_mut("silenced") {
+                    *silenced = rename_keys(silenced, |scope| pseudonyms.scope(scope));
+                }
+                if let Some(message) = options.get_mut("escalation_message") {
+                    *message = Value::from("");
+                }
+            }
+            ("state", Value::Object(state)) => {
+                if let Some(Value::Object(groups)) = state.get_mut("groups") {
+                    *groups = rename_keys(groups, |group| pseudonyms.scope(group));
+                    for group in groups.values_mut() {
+                        if let Some(Value::String(name)) = group.get_mut("name") {
+                            *name = pseudonyms.scope(name);
+                        }
+                    }
+                }
+            }
+            ("matching_downtimes", Value::Array(downtimes)) => {
+                for downtime in downtimes.iter_mut() {
+                    let scope: Vec<Value> = downtime
+                        .get("scope")
+                        .and_then(Value::as_array)
+                        .map(|scope| {
+                            scope
+                                .iter()
+                                .filter_map(Value::as_str)
+                                .map(|s| Value::from(pseudonyms.scope(s)))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    // only when the downtime applies matters, not who scheduled it or why
+                    *downtime = json!({
+                        "start": downtime.get("start").cloned().unwrap_or(Value::Null),
+                        "end": downtime.get("end").cloned().unwrap_or(Value::Null),
+                        "scope": scope,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn rename_keys<F: FnMut(&str) -> String>(
+    map: &Map<String, Value>,
+    mut rename: F,
+) -> Map<String, Value> {
+    map.iter()
+        .map(|(key, value)| (rename(key), value.clone()))
+        .collect()
+}
+
+/// Anonymises the monitor and records it as `{name}.json` in the fixtures directory, adding it to
+/// the index with the status it has now as the expected status of its unfiltered badge
+pub fn record(dir: &Path, name: &str, monitor: Value) -> io::Result<Fixture> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a valid fixture name", name),
+        ));
+    }
+    let file = format!("{}.json", name);
+    let path = dir.join(&file);
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} is already recorded, remove it and its entry in index.toml to record it again",
+                path.display()
+            ),
+        ));
+    }
+    let mut monitor = monitor;
+    anonymise(&mut monitor);
+    let state: MonitorState = serde_json::from_value(monitor.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let (status, since) = state.status(None);
+    let fixture = Fixture {
+        file,
+        monitor_type: monitor
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+        expect: vec![Expectation {
+            filter: None,
+            status,
+            since: since.map(|since| since.to_rfc3339()),
+        }],
+    };
+    let entry = toml::to_string(&FixtureIndex {
+        fixtures: vec![fixture.clone()],
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::create_dir_all(dir)?;
+    fs::write(&path, serde_json::to_string_pretty(&monitor)? + "\n")?;
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("index.toml"))?;
+    write!(index, "\n{}", entry)?;
+    Ok(fixture)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use chrono::{DateTime, Utc};
+    use serde_json::{json, Value};
+
+    use crate::datadog::{MonitorState, MonitorStatus};
+    use crate::fixture::{anonymise, record, FixtureIndex};
+
+    #[test]
+    fn test_anonymise() {
+        let mut monitor = json!({
+            "id": 12345,
+            "org_id": 98765,
+            "type": "metric alert",
+            "name": "CPU high on checkout",
+            "message": "@pagerduty-checkout {{host.name}} is hot",
+            "query": "avg(last_5m):avg:system.cpu.user{env:prod,service:checkout} by {host} > 90",
+            "tags": ["env:prod", "team:payments", "critical"],
+            "creator": {"email": "jane@acme.com", "handle": "jane@acme.com", "name": "Jane", "id": 42},
+            "options": {"silenced": {"host:web-01": null}},
+            "state": {"groups": {
+                "host:web-01,env:prod": {"name": "host:web-01,env:prod", "status": "Alert"},
+                "host:web-02,env:prod": {"name": "host:web-02,env:prod", "status": "OK"},
+            }},
+            "matching_downtimes": [{"id": 7, "message": "Patching", "start": 1, "end": 2, "scope": ["host:web-02"]}],
+        });
+        anonymise(&mut monitor);
+        assert_eq!(
+            monitor,
+            json!({
+                "id": 12345,
+                "org_id": 1,
+                "type": "metric alert",
+                "name": "Anonymised metric alert",
+                "message": "",
+                "query": "avg(last_5m):avg:system.cpu.user{env:env-1,service:service-1} by {host} > 90",
+                "tags": ["env:env-1", "team:team-1", "tag-1"],
+                "creator": {"email": "someone@example.com", "handle": "someone@example.com", "name": "Someone", "id": 1},
+                "options": {"silenced": {"host:host-2": null}},
+                "state": {"groups": {
+                    "host:host-2,env:env-1": {"name": "host:host-2,env:env-1", "status": "Alert"},
+                    "host:host-1,env:env-1": {"name": "host:host-1,env:env-1", "status": "OK"},
+                }},
+                "matching_downtimes": [{"start": 1, "end": 2, "scope": ["host:host-1"]}],
+            })
+        );
+    }
+
+    /// Every recorded monitor deserializes and shows the expected status for each filter
+    #[test]
+    fn test_replay() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_data/monitors");
+        let index = FixtureIndex::load(&dir).unwrap();
+        for fixture in &index.fixtures {
+            let text = fs::read_to_string(dir.join(&fixture.file)).unwrap();
+            let raw: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(
+                raw["type"],
+                fixture.monitor_type.as_str(),
+                "{}",
+                fixture.file
+            );
+            let monitor: MonitorState = serde_json::from_str(&text)
+                .unwrap_or_else(|e| panic!("{} does not deserialize: {}", fixture.file, e));
+            for expect in &fixture.expect {
+                let since = expect.since.as_ref().map(|since| {
+                    DateTime::parse_from_rfc3339(since)
+                        .unwrap()
+                        .with_timezone(&Utc)
+                });
+                assert_eq!(
+                    monitor.status(expect.filter.as_deref()),
+                    (expect.status, since),
+                    "{} with filter {:?}",
+                    fixture.file,
+                    expect.filter
+                );
+            }
+        }
+        // a recorded monitor left out of the index would never be replayed
+        let indexed: BTreeSet<_> = index.fixtures.iter().map(|f| f.file.clone()).collect();
+        let recorded: BTreeSet<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".json"))
+            .collect();
+        assert_eq!(indexed, recorded);
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = env::temp_dir().join(format!("datadog-badges-fixtures-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let monitor = json!({
+            "id": 1,
+            "type": "log alert",
+            "name": "Errors",
+            "overall_state": "Warn",
+            "overall_state_modified": "2024-05-01T10:15:00+00:00",
+            "tags": ["service:checkout"],
+        });
+        let fixture = record(&dir, "log", monitor.clone()).unwrap();
+        assert_eq!(fixture.expect[0].status, MonitorStatus::Warn);
+        assert!(record(&dir, "log", monitor.clone()).is_err());
+        assert!(record(&dir, "../escape", monitor).is_err());
+
+        let index = FixtureIndex::load(&dir).unwrap();
+        assert_eq!(index.fixtures, vec![fixture]);
+        let recorded: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("log.json")).unwrap()).unwrap();
+        assert_eq!(recorded["name"], "Anonymised log alert");
+        assert_eq!(recorded["tags"], json!(["service:service-1"]));
+        let state: MonitorState = serde_json::from_value(recorded).unwrap();
+        assert_eq!(state.status(None).0, MonitorStatus::Warn);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
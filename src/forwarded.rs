@@ -0,0 +1,68 @@
+/// The headers that a reverse proxy sets to describe the request that the client originally made
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Forwarded {
+    /// `X-Forwarded-Proto`
+    pub proto: Option<String>,
+    /// `X-Forwarded-Host`
+    pub host: Option<String>,
+    /// `X-Forwarded-Prefix`, the path the proxy mounts the server at
+    pub prefix: Option<String>,
+}
+
+/// The first of the comma separated values that each proxy in a chain appends to
+fn first_value(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .and_then(|v| v.split(',').next())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// The scheme, host and path prefix that the client reached the server through, to which the path
+/// of the request as the server received it is appended to build absolute URLs
+///
+/// The forwarded headers are only given when the server is behind a reverse proxy that sets them,
+/// as otherwise any client could choose the URLs that the server hands out.
+pub fn external_base(
+    default_scheme: &str,
+    host: Option<&str>,
+    forwarded: Option<&Forwarded>,
+) -> String {
+    let forwarded = forwarded.cloned().unwrap_or_default();
+    let scheme = first_value(&forwarded.proto).unwrap_or(default_scheme);
+    let host = first_value(&forwarded.host).or(host).unwrap_or("localhost");
+    let prefix = first_value(&forwarded.prefix)
+        .map(|p| p.trim_matches('/'))
+        .filter(|p| !p.is_empty())
+        .map(|p| format!("/{}", p))
+        .unwrap_or_default();
+    format!("{}://{}{}", scheme, host, prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forwarded::{external_base, Forwarded};
+
+    #[test]
+    fn test_external_base() {
+        assert_eq!(
+            external_base("http", Some("badges:8080"), Some(&Forwarded::default())),
+            "http://badges:8080"
+        );
+        assert_eq!(external_base("https", None, None), "https://localhost");
+        let forwarded = Forwarded {
+            proto: Some("https, http".to_owned()),
+            host: Some("tools.example.com".to_owned()),
+            prefix: Some("/tools/observability/".to_owned()),
+        };
+        assert_eq!(
+            external_base("http", Some("badges:8080"), Some(&forwarded)),
+            "https://tools.example.com/tools/observability"
+        );
+        // the headers of an untrusted client are ignored
+        assert_eq!(
+            external_base("http", Some("badges:8080"), None),
+            "http://badges:8080"
+        );
+    }
+}
@@ -0,0 +1,391 @@
+extern crate cached;
+extern crate chrono;
+
+use std::cmp::max;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+
+use cached::{Cached, SizedCache};
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::badge::escape_xml;
+use crate::datadog::{GroupStatus, MonitorStatus};
+use crate::pages::encode_uri_component;
+
+/// Identifies a badge by its account, monitor ID and the query parameters that affect its status
+pub type HistoryKey = (String, String, BTreeMap<String, String>);
+
+/// The key of the history of a badge, keeping only the `q` filter and `g` flag of the query so
+/// that other parameters do not start new histories
+pub fn history_key(account: &str, id: &str, query: &BTreeMap<String, String>) -> HistoryKey {
+    let mut status_query = BTreeMap::new();
+    if let Some(filter) = query.get("q").filter(|filter| !filter.is_empty()) {
+        status_query.insert("q".to_owned(), filter.clone());
+    }
+    if query.contains_key("g") {
+        status_query.insert("g".to_owned(), "true".to_owned());
+    }
+    (account.to_owned(), id.to_owned(), status_query)
+}
+
+/// A change of status observed by the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    /// The group that changed, or `None` for the badge as a whole
+    pub group: Option<String>,
+    pub from: MonitorStatus,
+    pub to: MonitorStatus,
+    /// When the monitor or group entered the new status
+    pub at: DateTime<Utc>,
+    /// Counts the transitions of the badge in the order they were observed, which restarts with
+    /// the server, so it only orders them and never identifies them
+    pub sequence: u64,
+}
+
+struct BadgeHistory {
+    last: MonitorStatus,
+    groups: BTreeMap<String, MonitorStatus>,
+    transitions: VecDeque<Transition>,
+    sequence: u64,
+}
+
+/// Remembers the most recent status transitions of a bounded number of badges, forgetting the
+/// least recently used badge when full
+pub struct TransitionHistory {
+    capacity: usize,
+    badges: SizedCache<HistoryKey, BadgeHistory>,
+}
+
+impl TransitionHistory {
+    /// Creates a history that remembers up to `capacity` transitions for each of up to
+    /// `max_badges` badges
+    pub fn new(capacity: usize, max_badges: usize) -> TransitionHistory {
+        TransitionHistory {
+            capacity,
+            badges: SizedCache::with_size(max(max_badges, 1)),
+        }
+    }
+
+    /// Records the latest status of the badge and of each of its groups, returning the
+    /// transitions of those that changed
+    pub fn record(
+        &mut self,
+        key: &HistoryKey,
+        status: MonitorStatus,
+        groups: &[GroupStatus],
+        at: DateTime<Utc>,
+    ) -> Vec<Transition> {
+        let groups: BTreeMap<String, (MonitorStatus, DateTime<Utc>)> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.name.clone(),
+                    (group.status, group.since.unwrap_or(at)),
+                )
+            })
+            .collect();
+        let mut history = match self.badges.cache_remove(key) {
+            Some(history) => history,
+            None => {
+                let history = BadgeHistory {
+                    last: status,
+                    groups: groups
+                        .iter()
+                        .map(|(name, (s, _))| (name.clone(), *s))
+                        .collect(),
+                    transitions: VecDeque::new(),
+                    sequence: 0,
+                };
+                self.badges.cache_set(key.clone(), history);
+                return Vec::new();
+            }
+        };
+        let mut changes: Vec<(Option<String>, MonitorStatus, MonitorStatus, DateTime<Utc>)> =
+            groups
+                .iter()
+                .filter_map(|(name, (to, since))| match history.groups.get(name) {
+                    Some(from) if from != to => Some((Some(name.clone()), *from, *to, *since)),
+                    _ => None,
+                })
+                .collect();
+        if history.last != status {
+            changes.push((None, history.last, status, at));
+        }
+        let mut recorded = Vec::with_capacity(changes.len());
+        for (group, from, to, at) in changes {
+            history.sequence += 1;
+            let transition = Transition {
+                group,
+                from,
+                to,
+                at,
+                sequence: history.sequence,
+            };
+            history.transitions.push_front(transition.clone());
+            recorded.push(transition);
+        }
+        history.transitions.truncate(self.capacity);
+        history.last = status;
+        history.groups = groups.into_iter().map(|(name, (s, _))| (name, s)).collect();
+        self.badges.cache_set(key.clone(), history);
+        recorded
+    }
+
+    /// The transitions of the badge, most recent first
+    pub fn transitions(&mut self, key: &HistoryKey) -> Vec<Transition> {
+        self.badges
+            .cache_get(key)
+            .map(|history| history.transitions.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// How many badges have a history
+    pub fn len(&self) -> usize {
+        self.badges.cache_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Renders the transitions of a badge as an Atom feed, served at the absolute URL `self_link`;
+/// `id` must be a URI, which the entry IDs extend with the time, statuses and group of each
+/// transition so that they stay the same across restarts
+pub fn atom_feed(
+    id: &str,
+    title: &str,
+    link: &str,
+    self_link: &str,
+    filter: Option<&str>,
+    transitions: &[Transition],
+) -> String {
+    let updated = transitions
+        .first()
+        .map(|t| t.at)
+        .unwrap_or_else(|| Utc.timestamp(0, 0));
+    let mut feed = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>{}</id>
+  <title>{}</title>
+  <link href="{}"/>
+  <link rel="self" href="{}"/>
+  <updated>{}</updated>
+  <author><name>datadog-badges</name></author>
+"#,
+        escape_xml(id),
+        escape_xml(title),
+        escape_xml(link),
+        escape_xml(self_link),
+        updated.to_rfc3339()
+    );
+    for transition in transitions {
+        let (entry_title, summary) = match &transition.group {
+            Some(group) => (
+                format!("{}: {} → {}", group, transition.from, transition.to),
+                format!(
+                    "{} changed from {} to {} for group {}",
+                    title, transition.from, transition.to, group
+                ),
+            ),
+            None => (
+                format!("{} → {}", transition.from, transition.to),
+                format!(
+                    "{} changed from {} to {}{}",
+                    title,
+                    transition.from,
+                    transition.to,
+                    match filter {
+                        Some(filter) => format!(" for {}", filter),
+                        None => String::new(),
+                    }
+                ),
+            ),
+        };
+        let mut entry_id = format!(
+            "{}:{}:{}:{}",
+            id,
+            transition.at.timestamp(),
+            encode_uri_component(&transition.from.to_string()),
+            encode_uri_component(&transition.to.to_string())
+        );
+        if let Some(group) = &transition.group {
+            entry_id.push(':');
+            entry_id.push_str(&encode_uri_component(group));
+        }
+        // writing to a String cannot fail
+        let _ = write!(
+            feed,
+            r#"  <entry>
+    <id>{}</id>
+    <title>{}</title>
+    <link href="{}"/>
+    <updated>{}</updated>
+    <summary>{}</summary>
+  </entry>
+"#,
+            escape_xml(&entry_id),
+            escape_xml(&entry_title),
+            escape_xml(link),
+            transition.at.to_rfc3339(),
+            escape_xml(&summary)
+        );
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use crate::datadog::{GroupStatus, MonitorStatus};
+    use crate::history::{atom_feed, history_key, TransitionHistory};
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp(seconds, 0)
+    }
+
+    fn key(id: &str) -> (String, String, BTreeMap<String, String>) {
+        history_key("team", id, &BTreeMap::new())
+    }
+
+    #[test]
+    fn test_record() {
+        let key = key("1");
+        let mut history = TransitionHistory::new(2, 10);
+        assert!(history
+            .record(&key, MonitorStatus::Ok, &[], at(1))
+            .is_empty());
+        assert!(history
+            .record(&key, MonitorStatus::Ok, &[], at(2))
+            .is_empty());
+        assert_eq!(
+            history.record(&key, MonitorStatus::Alert, &[], at(3)).len(),
+            1
+        );
+        assert_eq!(
+            history.record(&key, MonitorStatus::Warn, &[], at(4)).len(),
+            1
+        );
+        assert_eq!(history.record(&key, MonitorStatus::Ok, &[], at(5)).len(), 1);
+        let transitions = history.transitions(&key);
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[0].from, MonitorStatus::Warn);
+        assert_eq!(transitions[0].to, MonitorStatus::Ok);
+        assert_eq!(transitions[0].at, at(5));
+        assert_eq!(transitions[0].sequence, 3);
+        assert_eq!(transitions[1].to, MonitorStatus::Warn);
+        assert!(history.transitions(&self::key("2")).is_empty());
+    }
+
+    #[test]
+    fn test_record_groups() {
+        let key = key("1");
+        let group = |name: &str, status| GroupStatus {
+            name: name.to_owned(),
+            status,
+            since: None,
+        };
+        let mut history = TransitionHistory::new(10, 10);
+        let ok = [
+            group("env:prod", MonitorStatus::Ok),
+            group("env:staging", MonitorStatus::Ok),
+        ];
+        history.record(&key, MonitorStatus::Ok, &ok, at(1));
+        let alert = [
+            group("env:prod", MonitorStatus::Alert),
+            group("env:staging", MonitorStatus::Ok),
+        ];
+        let transitions = history.record(&key, MonitorStatus::Alert, &alert, at(2));
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[0].group.as_deref(), Some("env:prod"));
+        assert_eq!(transitions[0].to, MonitorStatus::Alert);
+        assert_eq!(transitions[1].group, None);
+        // both changed at the same time, so only the sequence tells them apart
+        assert_ne!(transitions[0].sequence, transitions[1].sequence);
+    }
+
+    #[test]
+    fn test_history_bounded() {
+        let mut query = BTreeMap::new();
+        query.insert("q".to_owned(), "env:prod".to_owned());
+        query.insert("g".to_owned(), "1".to_owned());
+        query.insert("x".to_owned(), "1".to_owned());
+        query.insert("ts".to_owned(), "123".to_owned());
+        let (_, _, status_query) = history_key("team", "1", &query);
+        assert_eq!(status_query.len(), 2);
+        assert_eq!(status_query["g"], "true");
+
+        let mut history = TransitionHistory::new(10, 2);
+        for id in &["1", "2"] {
+            history.record(&key(id), MonitorStatus::Ok, &[], at(1));
+            history.record(&key(id), MonitorStatus::Alert, &[], at(2));
+        }
+        // reading the first badge makes the second the least recently used
+        assert_eq!(history.transitions(&key("1")).len(), 1);
+        history.record(&key("3"), MonitorStatus::Ok, &[], at(3));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.transitions(&key("1")).len(), 1);
+        assert!(history.transitions(&key("2")).is_empty());
+    }
+
+    #[test]
+    fn test_atom_feed() {
+        let key = key("1");
+        let group = |status| {
+            [GroupStatus {
+                name: "env:prod".to_owned(),
+                status,
+                since: None,
+            }]
+        };
+        let feed = |history: &mut TransitionHistory| {
+            history.record(&key, MonitorStatus::Ok, &group(MonitorStatus::Ok), at(1));
+            history.record(
+                &key,
+                MonitorStatus::Alert,
+                &group(MonitorStatus::Alert),
+                at(60),
+            );
+            atom_feed(
+                "urn:x",
+                "Monitor 1 & co",
+                "https://team.datadoghq.com/monitors/1",
+                "https://badges.example.com/accounts/team/monitors/1/feed?q=env%3Aprod\u{1}",
+                Some("env:prod"),
+                &history.transitions(&key),
+            )
+        };
+        let mut history = TransitionHistory::new(10, 10);
+        // a transition seen before already has a higher sequence
+        history.record(&key, MonitorStatus::Warn, &[], at(0));
+        let first = feed(&mut history);
+        // a restarted server counts transitions from scratch, but must not repeat entries
+        let feed = feed(&mut TransitionHistory::new(10, 10));
+        let entry_ids = |feed: &str| -> Vec<String> {
+            feed.lines()
+                .filter(|line| line.starts_with("    <id>"))
+                .map(str::to_owned)
+                .collect()
+        };
+        assert_eq!(entry_ids(&first)[..2], entry_ids(&feed)[..]);
+        assert!(feed.contains("<title>Monitor 1 &amp; co</title>"));
+        assert!(feed.contains("<updated>1970-01-01T00:01:00+00:00</updated>"));
+        assert!(feed.contains(
+            r#"<link rel="self" href="https://badges.example.com/accounts/team/monitors/1/feed?q=env%3Aprod"/>"#
+        ));
+        assert!(feed.contains("<id>urn:x:60:Ok:Alert</id>"));
+        assert!(feed.contains("<id>urn:x:60:Ok:Alert:env%3Aprod</id>"));
+        assert!(feed.contains("<title>env:prod: Ok → Alert</title>"));
+        assert!(feed.contains(
+            "<summary>Monitor 1 &amp; co changed from Ok to Alert for group env:prod</summary>"
+        ));
+        assert!(feed.contains(
+            "<summary>Monitor 1 &amp; co changed from Ok to Alert for env:prod</summary>"
+        ));
+    }
+}
@@ -1,4 +1,19 @@
 extern crate reqwest;
 
 pub mod badge;
+pub mod cache;
+pub mod config;
 pub mod datadog;
+pub mod events;
+pub mod fixture;
+pub mod forwarded;
+pub mod history;
+pub mod logging;
+pub mod pages;
+pub mod persist;
+pub mod service;
+pub mod source;
+#[cfg(feature = "otlp")]
+pub mod telemetry;
+pub mod tls;
+pub mod webhook;
@@ -0,0 +1,26 @@
+// Keeps monitor and alias badges up to date without reloading the page.
+//
+// Mark each badge image with a `data-live-badge` attribute and include this script:
+//
+//   <img src="https://hostname/accounts/example-team/monitors/12345" data-live-badge>
+//   <img src="https://hostname/badges/checkout" data-live-badge>
+//   <script src="https://hostname/live.js" async></script>
+(function () {
+    if (!window.EventSource || !window.URL) {
+        return;
+    }
+    var images = document.querySelectorAll("img[data-live-badge]");
+    for (var i = 0; i < images.length; i++) {
+        (function (image) {
+            var url = new URL(image.src, document.baseURI);
+            url.pathname = url.pathname.replace(/\/$/, "") + "/events";
+            url.searchParams.delete("ts");
+            var source = new EventSource(url.toString());
+            source.addEventListener("badge", function (event) {
+                var badge = JSON.parse(event.data);
+                image.src = badge.svg;
+                image.alt = badge.status;
+            });
+        })(images[i]);
+    }
+})();
@@ -3,44 +3,151 @@ extern crate datadog_badges;
 extern crate env_logger;
 #[macro_use]
 extern crate log;
+extern crate tracing;
 
+#[cfg(test)]
+mod mock_datadog;
+
+use std::cmp::min;
 use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::env;
-use std::net::ToSocketAddrs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
 
 use cached::once_cell::sync::Lazy;
-use cached::{Cached, TimedCache};
 use chrono::Utc;
 use env_logger::Env;
+use futures::future::{self, join_all, BoxFuture, Either, FutureExt, Shared};
+use futures::Future;
+use futures::{StreamExt, TryFutureExt};
 use getopts::Options;
-use regex::Regex;
+use reqwest::{Client, Url};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time;
+use tokio_rustls::{server, TlsAcceptor};
+use tracing::{field, info_span, Instrument};
+#[cfg(feature = "otlp")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use warp::filters::path::FullPath;
+use warp::http::{HeaderValue, Response};
+use warp::hyper::server::accept;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
 use warp::reject::not_found;
-use warp::{http::Response, Filter, Rejection};
+use warp::{hyper, Filter, Rejection, Reply};
 
-use datadog_badges::badge::{
-    Badge, BadgeOptions, COLOR_DANGER, COLOR_OTHER, COLOR_SUCCESS, COLOR_WARNING,
+use datadog_badges::badge::{etag, etag_matches, Badge, BadgeOptions};
+use datadog_badges::cache::{MemoryCache, MonitorCache, RedisCache};
+use datadog_badges::config::{Alias, Config, TlsConfig};
+use datadog_badges::datadog::{
+    build_client, get_monitor_json, read_certificates, ClientOptions, Credentials, Target,
+    DEFAULT_API_URL,
 };
-use datadog_badges::datadog::{get_monitor_details, MonitorState, MonitorStatus};
+use datadog_badges::events::{EventHub, StreamKey};
+use datadog_badges::fixture;
+use datadog_badges::forwarded::{self, Forwarded};
+use datadog_badges::history::{atom_feed, history_key, TransitionHistory};
+use datadog_badges::logging::{self, LogFormat, RequestLog};
+use datadog_badges::pages;
+use datadog_badges::persist;
+use datadog_badges::service::{BadgeService, CacheTtls, Freshness, MonitorBadge};
+use datadog_badges::source::DatadogSource;
+#[cfg(feature = "otlp")]
+use datadog_badges::telemetry;
+use datadog_badges::tls::{https_location, ReloadingCertificate};
+use datadog_badges::webhook::{parse_payload, secret_matches};
 
-async fn get_monitor_badge(
-    status_codes: bool,
-    account: String,
-    id: String,
-    query: BTreeMap<String, String>,
-) -> Result<Response<String>, Rejection> {
-    static MAX_AGE_SECONDS: Lazy<u64> = Lazy::new(|| match env::var("CACHE_TTL_SECONDS") {
-        Ok(value) => value.parse::<u64>().unwrap_or(15),
-        Err(_) => 15,
+static MAX_AGE_SECONDS: Lazy<u64> = Lazy::new(|| match env::var("CACHE_TTL_SECONDS") {
+    Ok(value) => value.parse::<u64>().unwrap_or(15),
+    Err(_) => 15,
+});
+
+/// Reads a number of seconds from the environment variable, falling back to `default`
+fn env_seconds(name: &str, default: u64) -> Duration {
+    Duration::from_secs(match env::var(name) {
+        Ok(value) => value.parse::<u64>().unwrap_or(default),
+        Err(_) => default,
+    })
+}
+
+/// How old a persisted monitor can be and still be restored when the server starts
+static CACHE_FILE_MAX_AGE_SECONDS: Lazy<i64> =
+    Lazy::new(|| match env::var("CACHE_FILE_MAX_AGE_SECONDS") {
+        Ok(value) => value.parse::<i64>().unwrap_or(600),
+        Err(_) => 600,
     });
-    type CacheKey = (String, String, BTreeMap<String, String>);
-    type CacheValue = (BadgeOptions, u16);
-    type BadgeCache = TimedCache<CacheKey, CacheValue>;
-    static BADGE_CACHE: Lazy<Mutex<BadgeCache>> =
-        Lazy::new(|| Mutex::new(TimedCache::with_lifespan(*MAX_AGE_SECONDS)));
 
-    let mut query = query.clone();
+/// Saves the monitors on the blocking thread pool, as writing and syncing the file would stall
+/// the requests sharing the runtime thread
+async fn save_monitor_cache(cache: &MemoryCache, path: &Path) {
+    if let Some(monitors) = cache.snapshot(*CACHE_FILE_MAX_AGE_SECONDS) {
+        let count = monitors.len();
+        let file = path.to_owned();
+        let result = task::spawn_blocking(move || persist::save(&file, monitors))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match result {
+            Ok(()) => debug!("Saved {} monitors to {}", count, path.display()),
+            Err(e) => warn!("Could not save monitors to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Records each `{account}/{monitor}={name}` monitor as a test fixture, returning the exit code
+async fn record_fixtures(client: &Client, api_url: &str, dir: &Path, fixtures: &[String]) -> i32 {
+    let mut code = 0;
+    for fixture in fixtures {
+        let (account, id, name) = match fixture.split_once('=').and_then(|(monitor, name)| {
+            monitor
+                .split_once('/')
+                .map(|(account, id)| (account, id, name))
+        }) {
+            Some(parsed) => parsed,
+            None => {
+                error!(
+                    "Invalid --record {}, expected ACCOUNT/MONITOR=NAME",
+                    fixture
+                );
+                code = 1;
+                continue;
+            }
+        };
+        let monitor = match Credentials::from_env(account) {
+            Ok(credentials) => get_monitor_json(client, api_url, &credentials, id).await,
+            Err(e) => Err(e),
+        };
+        let recorded = monitor
+            .map_err(|e| e.to_string())
+            .and_then(|monitor| fixture::record(dir, name, monitor).map_err(|e| e.to_string()));
+        match recorded {
+            Ok(fixture) => info!(
+                "Recorded monitor {} of {} in {} as {}, expecting {}",
+                id,
+                account,
+                dir.display(),
+                fixture.file,
+                fixture.expect[0].status
+            ),
+            Err(e) => {
+                error!("Could not record monitor {} of {}: {}", id, account, e);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+/// Removes the query parameters that only affect how the badge is presented, returning the
+/// requested image width and height
+fn split_image_size(query: &mut BTreeMap<String, String>) -> (Option<u32>, Option<u32>) {
     // remove the `ts` query parameter that browser-side JavaScript may inject to force browser refetch
     query.remove("ts");
     // remove the width overrode
@@ -51,130 +158,653 @@ async fn get_monitor_badge(
     let height = query
         .remove("h")
         .and_then(|v| v.parse().map(Some).unwrap_or(None));
+    (width, height)
+}
 
-    let query = query;
+/// Removes the `max_age` query parameter, bounding it by the configured minimum and the longest
+/// time the cache keeps monitors
+fn split_max_age(service: &BadgeService, query: &mut BTreeMap<String, String>) -> Option<u64> {
+    query
+        .remove("max_age")
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|v| service.bound_max_age(v))
+}
 
-    let key = (account.clone(), id.clone(), query.clone());
-    let max_age: u64 = *MAX_AGE_SECONDS;
-    {
-        let mut cache = BADGE_CACHE.lock().unwrap();
-        if let Some((options, status_code)) = cache.cache_get(&key) {
-            return Response::builder()
-                .status(*status_code)
-                .header("Content-Type", "image/svg+xml")
-                .header("Cache-Control", format!("public,max-age={}", max_age))
-                .body(
-                    Badge::new(BadgeOptions {
-                        width,
-                        height,
-                        ..options.clone()
-                    })
-                    .to_svg(),
-                )
-                .map_err(|_| not_found());
+#[tracing::instrument(skip_all, fields(account = %account, monitor_id = %target.id()))]
+async fn get_monitor_badge(
+    service: Arc<BadgeService>,
+    status_codes: bool,
+    account: String,
+    target: Target,
+    query: BTreeMap<String, String>,
+    alias: Option<&Alias>,
+    if_none_match: Option<String>,
+) -> Result<Response<String>, Rejection> {
+    let mut query = query;
+    let (width, height) = split_image_size(&mut query);
+    let freshness = Freshness {
+        alias_ttl: alias.and_then(|alias| alias.ttl),
+        max_age: split_max_age(&service, &mut query),
+    };
+    let no_cache = query.remove("no_cache").is_some()
+        || alias.is_some_and(|alias| alias.no_cache)
+        || service.no_cache(&account);
+    let MonitorBadge {
+        options,
+        status_code,
+        since,
+        fresh_for: max_age,
+    } = service
+        .monitor_badge(&account, &target, &query, freshness)
+        .await;
+    let status_code = if status_codes { status_code } else { 200 };
+    logging::record(|log| log.badge_status = Some(options.status.clone()));
+    let svg = Badge::new(BadgeOptions {
+        width,
+        height,
+        ..options
+    })
+    .to_svg();
+    let etag = etag(&svg);
+    // only successful responses can be revalidated
+    let not_modified =
+        status_code == 200 && if_none_match.is_some_and(|header| etag_matches(&header, &etag));
+    let mut response = Response::builder()
+        .status(if not_modified { 304 } else { status_code })
+        .header("Content-Type", "image/svg+xml")
+        .header("ETag", etag);
+    response = if no_cache {
+        // image proxies such as GitHub's camo otherwise keep serving stale badges for hours
+        response
+            .header("Cache-Control", "no-cache, no-store, must-revalidate")
+            .header("Expires", "Thu, 01 Jan 1970 00:00:00 GMT")
+            .header("Pragma", "no-cache")
+    } else {
+        response.header("Cache-Control", format!("public,max-age={}", max_age))
+    };
+    if let Some(since) = since {
+        response = response.header(
+            "Last-Modified",
+            since.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        );
+    }
+    response
+        .body(if not_modified { String::new() } else { svg })
+        .map_err(|_| not_found())
+}
+
+async fn get_monitor_events(
+    service: Arc<BadgeService>,
+    hub: Arc<EventHub>,
+    account: String,
+    target: Target,
+    query: BTreeMap<String, String>,
+    alias_ttl: Option<u64>,
+) -> Result<Response<hyper::Body>, Rejection> {
+    let mut query = query;
+    let (width, height) = split_image_size(&mut query);
+    let max_age = split_max_age(&service, &mut query);
+    let subscription = hub.subscribe(StreamKey {
+        account,
+        target,
+        query,
+        width,
+        height,
+        max_age,
+        alias_ttl,
+    });
+    match subscription {
+        Some(subscription) => {
+            let events = subscription.map(|event| {
+                Ok::<_, Infallible>((
+                    warp::sse::event("badge"),
+                    warp::sse::json(event.as_ref().clone()),
+                ))
+            });
+            Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+        }
+        None => {
+            warn!("Not streaming the badge, as too many badges are live already");
+            Response::builder()
+                .status(503)
+                .header("Retry-After", "60")
+                .body(hyper::Body::empty())
+                .map_err(|_| not_found())
         }
     }
-    let client = reqwest::Client::new();
-    let env_root = account.to_string().to_uppercase();
-    let env_root = Regex::new(r"[^A-Z0-9_]")
-        .unwrap()
-        .replace_all(&env_root, "_");
-    let app_key = env::var(format!("{}_DATADOG_APP_KEY", env_root));
-    let api_key = env::var(format!("{}_DATADOG_API_KEY", env_root));
-    let value = if let (Ok(api_key), Ok(app_key)) = (api_key, app_key) {
-        let details =
-            get_monitor_details(&client, &api_key, &app_key, &id, query.get("g").is_some()).await;
-        match details {
-            Err(_) => (
-                BadgeOptions {
-                    status: "HTTP/500 Internal Server Error".to_owned(),
-                    color: COLOR_WARNING.to_owned(),
-                    width,
-                    height,
-                    ..BadgeOptions::default()
-                },
-                if status_codes { 500 } else { 200 },
-            ),
-            Ok(response) => {
-                if response.status().is_success() {
-                    let value: MonitorState = response.json().await.map_err(|_| not_found())?;
-                    let (status, since) = value.status(query.get("q").map(String::as_ref));
-                    (
-                        BadgeOptions {
-                            duration: match since {
-                                Some(v) => Some(Utc::now().signed_duration_since(v)),
-                                None => None,
-                            },
-                            color: match &status {
-                                MonitorStatus::Ok | MonitorStatus::Skipped => {
-                                    COLOR_SUCCESS.to_owned()
-                                }
-                                MonitorStatus::Alert | MonitorStatus::Unknown => {
-                                    COLOR_DANGER.to_owned()
-                                }
-                                MonitorStatus::Warn => COLOR_WARNING.to_owned(),
-                                MonitorStatus::NoData | MonitorStatus::Ignored => {
-                                    COLOR_OTHER.to_owned()
-                                }
-                            },
-                            status: match &status {
-                                MonitorStatus::Ignored => "Ignored".to_owned(),
-                                MonitorStatus::Skipped => "Skipped".to_owned(),
-                                MonitorStatus::Ok => "Ok".to_owned(),
-                                MonitorStatus::Alert => "Alert".to_owned(),
-                                MonitorStatus::Unknown => "Unknown".to_owned(),
-                                MonitorStatus::Warn => "Warn".to_owned(),
-                                MonitorStatus::NoData => "No Data".to_owned(),
-                            },
-                            muted: !value.options.silenced.is_empty(),
-                            width,
-                            height,
-                        },
-                        200,
-                    )
-                } else {
-                    (
-                        BadgeOptions {
-                            status: response.status().as_str().to_owned(),
-                            color: COLOR_WARNING.to_owned(),
-                            width,
-                            height,
-                            ..BadgeOptions::default()
-                        },
-                        if status_codes {
-                            response.status().as_u16()
-                        } else {
-                            200
-                        },
-                    )
-                }
+}
+
+async fn get_alias_events(
+    service: Arc<BadgeService>,
+    hub: Arc<EventHub>,
+    config: Arc<Config>,
+    name: String,
+    query: BTreeMap<String, String>,
+) -> Result<Response<hyper::Body>, Rejection> {
+    let alias = match config.aliases.get(&name) {
+        Some(alias) => alias,
+        None => return Err(not_found()),
+    };
+    // as for the badge of the alias, only the image size and caching can be overridden
+    let mut query: BTreeMap<String, String> = query
+        .into_iter()
+        .filter(|(k, _)| k == "w" || k == "h" || k == "max_age")
+        .collect();
+    query.extend(alias.query.clone());
+    get_monitor_events(
+        service,
+        hub,
+        alias.account.clone(),
+        alias.target(),
+        query,
+        alias.ttl,
+    )
+    .await
+}
+
+async fn get_alias_badge(
+    service: Arc<BadgeService>,
+    status_codes: bool,
+    config: Arc<Config>,
+    name: String,
+    query: BTreeMap<String, String>,
+    if_none_match: Option<String>,
+) -> Result<Response<String>, Rejection> {
+    let alias = match config.aliases.get(&name) {
+        Some(alias) => alias,
+        None => return Err(not_found()),
+    };
+    // only the image size and caching can be overridden by the caller, everything else is fixed by the alias
+    let mut query: BTreeMap<String, String> = query
+        .into_iter()
+        .filter(|(k, _)| k == "w" || k == "h" || k == "max_age" || k == "no_cache")
+        .collect();
+    query.extend(alias.query.clone());
+    get_monitor_badge(
+        service,
+        status_codes,
+        alias.account.clone(),
+        alias.target(),
+        query,
+        Some(alias),
+        if_none_match,
+    )
+    .await
+}
+
+async fn receive_webhook(
+    service: Arc<BadgeService>,
+    secret: Option<Arc<String>>,
+    supplied: Option<String>,
+    body: warp::hyper::body::Bytes,
+) -> Result<Response<String>, Rejection> {
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Err(not_found()),
+    };
+    let status = match supplied {
+        Some(supplied) if secret_matches(&secret, &supplied) => match parse_payload(&body) {
+            Ok(payload) => {
+                let evicted = service.evict(&payload.account, &payload.monitor_id).await;
+                info!(
+                    "Webhook for monitor {} of {} ({}) evicted {} cached monitors",
+                    payload.monitor_id,
+                    payload.account,
+                    payload
+                        .transition
+                        .as_deref()
+                        .unwrap_or("unknown transition"),
+                    evicted
+                );
+                204
+            }
+            Err(e) => {
+                warn!("Could not parse webhook payload: {}", e);
+                400
             }
+        },
+        _ => {
+            warn!("Rejected webhook with missing or incorrect secret");
+            401
         }
-    } else {
-        (
-            BadgeOptions {
-                status: format!("Unconfigured account: {}", account),
-                color: COLOR_OTHER.to_owned(),
-                width,
-                height,
-                ..BadgeOptions::default()
-            },
-            if status_codes { 404 } else { 200 },
-        )
     };
-    {
-        let mut cache = BADGE_CACHE.lock().unwrap();
-        cache.cache_set(key, value.clone())
+    Response::builder()
+        .status(status)
+        .body(String::new())
+        .map_err(|_| not_found())
+}
+
+async fn get_status_page(
+    service: Arc<BadgeService>,
+    config: Arc<Config>,
+) -> Result<Response<String>, Rejection> {
+    let page = match &config.status_page {
+        Some(page) => page,
+        None => return Err(not_found()),
+    };
+    let mut sections = Vec::with_capacity(page.sections.len());
+    let service = &service;
+    let mut max_age = None;
+    for section in &page.sections {
+        let entries = join_all(section.badges.iter().filter_map(|badge| {
+            config.resolve(&badge.target).map(|monitor| async move {
+                let target = monitor.target();
+                let freshness = Freshness {
+                    alias_ttl: monitor.ttl,
+                    max_age: None,
+                };
+                let MonitorBadge {
+                    options,
+                    since,
+                    fresh_for,
+                    ..
+                } = service
+                    .monitor_badge(&monitor.account, &target, &monitor.query, freshness)
+                    .await;
+                let entry = pages::StatusPageEntry {
+                    name: badge.name.clone(),
+                    link: target.web_link(&monitor.account),
+                    options,
+                    since,
+                };
+                (entry, fresh_for)
+            })
+        }))
+        .await
+        .into_iter()
+        .map(|(entry, fresh_for)| {
+            // the page is only fresh for as long as its stalest badge
+            max_age = Some(max_age.map_or(fresh_for, |v| min(v, fresh_for)));
+            entry
+        })
+        .collect();
+        sections.push((section.title.clone(), entries));
     }
-    let (options, status_code) = value;
     Response::builder()
-        .status(status_code)
-        .header("Content-Type", "image/svg+xml")
+        .header("Content-Type", "text/html; charset=UTF-8")
+        .header(
+            "Cache-Control",
+            format!("public,max-age={}", max_age.unwrap_or(*MAX_AGE_SECONDS)),
+        )
+        .body(pages::status_page(page, &sections))
+        .map_err(|_| not_found())
+}
+
+async fn get_embed_page(
+    prefix: Arc<String>,
+    account: String,
+    id: String,
+    base: String,
+    query: BTreeMap<String, String>,
+) -> Result<Response<String>, Rejection> {
+    let filter = query.get("q").cloned().unwrap_or_default();
+    let groups = query.contains_key("g");
+    let mut badge_query = BTreeMap::new();
+    if !filter.is_empty() {
+        badge_query.insert("q", filter.as_str());
+    }
+    if groups {
+        badge_query.insert("g", "true");
+    }
+    // the segments are as received, decode them so that they are encoded exactly once
+    let account = pages::decode_uri_component(&account);
+    let id = pages::decode_uri_component(&id);
+    let target = Target::monitor(&id).ok_or_else(not_found)?;
+    let badge_url = format!(
+        "{}{}/accounts/{}/monitors/{}",
+        base,
+        prefix,
+        pages::encode_uri_component(&account),
+        pages::encode_uri_component(&id)
+    );
+    let badge_url = match serde_urlencoded::to_string(&badge_query) {
+        Ok(query) if !query.is_empty() => format!("{}?{}", badge_url, query),
+        _ => badge_url,
+    };
+    let link = target.web_link(&account);
+    Response::builder()
+        .header("Content-Type", "text/html; charset=UTF-8")
+        .body(pages::embed_page(
+            &format!("Embed monitor {} of {}", id, account),
+            &badge_url,
+            &link,
+            &filter,
+            groups,
+        ))
+        .map_err(|_| not_found())
+}
+
+async fn get_monitor_feed(
+    service: Arc<BadgeService>,
+    account: String,
+    target: Target,
+    query: BTreeMap<String, String>,
+    alias_ttl: Option<u64>,
+    self_link: String,
+) -> Result<Response<String>, Rejection> {
+    let mut query = query;
+    split_image_size(&mut query);
+    let freshness = Freshness {
+        alias_ttl,
+        max_age: split_max_age(&service, &mut query),
+    };
+    // observe the current status so that the feed is up to date
+    let max_age = service
+        .monitor_badge(&account, &target, &query, freshness)
+        .await
+        .fresh_for;
+    let id = target.id();
+    let key = history_key(&account, &id, &query);
+    let transitions = service.transitions(&key);
+    // the query is form-encoded and so cannot contain a colon
+    let feed_id = format!(
+        "urn:datadog-badges:{}:{}",
+        pages::encode_uri_component(&pages::decode_uri_component(&account)),
+        pages::encode_uri_component(&pages::decode_uri_component(&id))
+    );
+    let feed_id = match serde_urlencoded::to_string(&key.2) {
+        Ok(query) if !query.is_empty() => format!("{}:{}", feed_id, query),
+        _ => feed_id,
+    };
+    Response::builder()
+        .header("Content-Type", "application/atom+xml; charset=UTF-8")
         .header("Cache-Control", format!("public,max-age={}", max_age))
-        .body(Badge::new(options).to_svg())
+        .body(atom_feed(
+            &feed_id,
+            &format!("Monitor {} of {}", id, account),
+            &target.web_link(&account),
+            &self_link,
+            query.get("q").map(String::as_str),
+            &transitions,
+        ))
         .map_err(|_| not_found())
 }
 
+async fn get_alias_feed(
+    service: Arc<BadgeService>,
+    config: Arc<Config>,
+    name: String,
+    self_link: String,
+) -> Result<Response<String>, Rejection> {
+    match config.aliases.get(&name) {
+        Some(alias) => {
+            get_monitor_feed(
+                service,
+                alias.account.clone(),
+                alias.target(),
+                alias.query.clone(),
+                alias.ttl,
+                self_link,
+            )
+            .await
+        }
+        None => Err(not_found()),
+    }
+}
+
+/// The monitor ID of the path segment, rejecting the IDs that SLOs and tag queries are cached by as
+/// those are only shown through aliases
+fn monitor_target() -> impl Filter<Extract = (Target,), Error = Rejection> + Clone {
+    warp::path::param().and_then(|id: String| async move {
+        // the segment is as received, and the prefixes would show once decoded
+        match Target::monitor(&pages::decode_uri_component(&id)) {
+            Some(_) => Ok(Target::Monitor(id)),
+            None => Err(not_found()),
+        }
+    })
+}
+
+/// The path and query string of the request
+fn path_and_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(|path: FullPath, query: String| {
+            if query.is_empty() {
+                path.as_str().to_owned()
+            } else {
+                format!("{}?{}", path.as_str(), query)
+            }
+        })
+}
+
+/// The scheme, host and reverse proxy prefix that the client reached the server through, trusting
+/// the `X-Forwarded-*` headers only if told to
+fn external_base(
+    scheme: &'static str,
+    trust_forwarded: bool,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("Host")
+        .and(warp::header::optional::<String>("X-Forwarded-Proto"))
+        .and(warp::header::optional::<String>("X-Forwarded-Host"))
+        .and(warp::header::optional::<String>("X-Forwarded-Prefix"))
+        .map(move |host: Option<String>, proto, forwarded_host, prefix| {
+            let forwarded = Forwarded {
+                proto,
+                host: forwarded_host,
+                prefix,
+            };
+            forwarded::external_base(
+                scheme,
+                host.as_deref(),
+                Some(&forwarded).filter(|_| trust_forwarded),
+            )
+        })
+}
+
+/// The absolute URL of the request, as the client made it
+fn self_link(
+    scheme: &'static str,
+    trust_forwarded: bool,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    external_base(scheme, trust_forwarded)
+        .and(path_and_query())
+        .map(|base: String, path_and_query: String| format!("{}{}", base, path_and_query))
+}
+
+/// What the routes need to know from the command line
+struct RouteOptions {
+    /// Whether error badges are served with a status code describing the error, not HTTP/200
+    status_codes: bool,
+    /// The path segments of the context root
+    segments: Vec<String>,
+    /// The scheme that clients reach the server through, unless a reverse proxy says otherwise
+    scheme: &'static str,
+    /// Whether the server is behind a reverse proxy that sets the `X-Forwarded-*` headers, which
+    /// are ignored otherwise
+    trust_forwarded: bool,
+    /// The secret that Datadog webhooks must present, without which webhooks are not served
+    webhook_secret: Option<Arc<String>>,
+    /// How many badges the live badge event streams can poll at once
+    live_badges: usize,
+}
+
+/// Every route of the server, logging each request
+fn routes(
+    service: Arc<BadgeService>,
+    config: Arc<Config>,
+    options: RouteOptions,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let RouteOptions {
+        status_codes,
+        segments,
+        scheme,
+        trust_forwarded,
+        webhook_secret,
+        live_badges,
+    } = options;
+    let prefix: Arc<String> = Arc::new(segments.iter().map(|s| format!("/{}", s)).collect());
+    let monitor_badge = {
+        let service = service.clone();
+        warp::path("accounts")
+            .and(warp::path::param())
+            .and(warp::path("monitors"))
+            .and(monitor_target())
+            .and(warp::path::end())
+            .and(warp::query::query())
+            .and(warp::header::optional("If-None-Match"))
+            .and_then(move |account, id, query, if_none_match| {
+                get_monitor_badge(
+                    service.clone(),
+                    status_codes,
+                    account,
+                    id,
+                    query,
+                    None,
+                    if_none_match,
+                )
+            })
+    };
+    let hub = Arc::new(EventHub::new(service.clone(), live_badges));
+    let monitor_events = {
+        let service = service.clone();
+        let hub = hub.clone();
+        warp::path("accounts")
+            .and(warp::path::param())
+            .and(warp::path("monitors"))
+            .and(monitor_target())
+            .and(warp::path("events"))
+            .and(warp::path::end())
+            .and(warp::query::query())
+            .and_then(move |account, target, query| {
+                get_monitor_events(service.clone(), hub.clone(), account, target, query, None)
+            })
+    };
+    let embed_page = warp::path("accounts")
+        .and(warp::path::param())
+        .and(warp::path("monitors"))
+        .and(warp::path::param())
+        .and(warp::path("embed"))
+        .and(warp::path::end())
+        .and(external_base(scheme, trust_forwarded))
+        .and(warp::query::query())
+        .and_then(move |account, id, base, query| {
+            get_embed_page(prefix.clone(), account, id, base, query)
+        });
+    let monitor_feed = {
+        let service = service.clone();
+        warp::path("accounts")
+            .and(warp::path::param())
+            .and(warp::path("monitors"))
+            .and(monitor_target())
+            .and(warp::path("feed"))
+            .and(warp::path::end())
+            .and(warp::query::query())
+            .and(self_link(scheme, trust_forwarded))
+            .and_then(move |account, id, query, self_link| {
+                get_monitor_feed(service.clone(), account, id, query, None, self_link)
+            })
+    };
+    let live_js = warp::path("live.js").and(warp::path::end()).map(|| {
+        Response::builder()
+            .header("Content-Type", "application/javascript; charset=UTF-8")
+            .header("Cache-Control", "public,max-age=3600")
+            .body(include_str!("live.js"))
+    });
+    let status_page = {
+        let service = service.clone();
+        let config = config.clone();
+        warp::path::end().and_then(move || get_status_page(service.clone(), config.clone()))
+    };
+    let alias_feed = {
+        let service = service.clone();
+        let config = config.clone();
+        warp::path("badges")
+            .and(warp::path::param())
+            .and(warp::path("feed"))
+            .and(warp::path::end())
+            .and(self_link(scheme, trust_forwarded))
+            .and_then(move |name, self_link| {
+                get_alias_feed(service.clone(), config.clone(), name, self_link)
+            })
+    };
+    let alias_events = {
+        let service = service.clone();
+        let config = config.clone();
+        warp::path("badges")
+            .and(warp::path::param())
+            .and(warp::path("events"))
+            .and(warp::path::end())
+            .and(warp::query::query())
+            .and_then(move |name, query| {
+                get_alias_events(service.clone(), hub.clone(), config.clone(), name, query)
+            })
+    };
+    let alias_badge = {
+        let service = service.clone();
+        warp::path("badges")
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::query::query())
+            .and(warp::header::optional("If-None-Match"))
+            .and_then(move |name, query, if_none_match| {
+                get_alias_badge(
+                    service.clone(),
+                    status_codes,
+                    config.clone(),
+                    name,
+                    query,
+                    if_none_match,
+                )
+            })
+    };
+    let webhook = warp::post()
+        .and(warp::path("webhooks"))
+        .and(warp::path("datadog"))
+        .and(warp::path::end())
+        .and(warp::header::optional("X-Webhook-Secret"))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::bytes())
+        .and_then(move |supplied, body| {
+            receive_webhook(service.clone(), webhook_secret.clone(), supplied, body)
+        });
+    let badges = monitor_badge
+        .or(monitor_events)
+        .or(embed_page)
+        .or(monitor_feed)
+        .or(live_js)
+        .or(alias_badge)
+        .or(alias_events)
+        .or(alias_feed)
+        .or(status_page)
+        .or(webhook);
+    let fallback = warp::any().map(|| {
+        Response::builder()
+            .status(404)
+            .header("Content-Type", "text/html; charset=UTF-8")
+            .body(pages::not_found())
+    });
+
+    let mut mount = warp::any().boxed();
+    for segment in segments {
+        mount = mount.and(warp::path(segment)).boxed();
+    }
+    mount
+        .and(badges)
+        .or(fallback)
+        .boxed()
+        .with(warp::log::custom(access_log))
+}
+
+fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::from_env(Env::default().default_filter_or("info,access=info"));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                logging::json_record(
+                    Utc::now(),
+                    &record.level().to_string(),
+                    record.target(),
+                    &record.args().to_string(),
+                    logging::current().as_ref(),
+                )
+            )
+        });
+    }
+    builder.init();
+}
+
 fn print_usage(program: &str, opts: &Options) {
     let brief = format!("Usage: {} [options]", program);
     println!("{}", opts.usage(&brief));
@@ -183,11 +813,6 @@ fn print_usage(program: &str, opts: &Options) {
 
 #[tokio::main]
 async fn main() {
-    let _ = ctrlc::set_handler(|| {
-        info!("Stopped");
-        exit(0)
-    });
-    env_logger::from_env(Env::default().default_filter_or("info,access=info")).init();
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu and exit");
     opts.optflag("V", "version", "print the version and exit");
@@ -209,6 +834,95 @@ async fn main() {
         "always-ok",
         "Always return images with status code HTTP/200",
     );
+    opts.optflag(
+        "",
+        "trust-forwarded",
+        "build absolute URLs from the X-Forwarded-* headers of a reverse proxy",
+    );
+    opts.optopt(
+        "",
+        "config",
+        "the configuration file to load badge aliases from",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "cache-file",
+        "the file to persist the monitor cache to across restarts",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "redis-url",
+        "the Redis server to share the monitor cache with other replicas",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "tls-cert",
+        "the PEM file of the certificate chain to serve HTTPS with",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "tls-key",
+        "the PEM file of the private key to serve HTTPS with",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "shutdown-timeout",
+        "how long to wait for in-flight requests when stopping (default: 30)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "redirect-port",
+        "the port to redirect plain HTTP requests to HTTPS from",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "api-url",
+        "the Datadog API to fetch monitors from (default: DATADOG_API_URL or https://api.datadoghq.com)",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "proxy",
+        "the proxy to send requests to Datadog through (default: HTTPS_PROXY)",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        "the format of the logs, text or json (default: LOG_FORMAT or text)",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "otlp-endpoint",
+        "the OpenTelemetry collector to export traces to over OTLP/HTTP, with the otlp feature (default: OTEL_EXPORTER_OTLP_ENDPOINT)",
+        "URL",
+    );
+    opts.optmulti(
+        "",
+        "record",
+        "fetch the monitor and record it anonymised as a test fixture, then exit",
+        "ACCOUNT/MONITOR=NAME",
+    );
+    opts.optopt(
+        "",
+        "fixtures-dir",
+        "the directory to record test fixtures in (default: src/test_data/monitors)",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "ca-file",
+        "a PEM file of extra root certificates to trust when connecting to Datadog",
+        "FILE",
+    );
 
     // set up to parse the command line options
     const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -216,7 +930,7 @@ async fn main() {
     let program = args[0].clone();
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => panic!("{}", f),
     };
 
     // process and validate the command line options
@@ -228,6 +942,24 @@ async fn main() {
         println!("{}", VERSION);
         return;
     }
+    let log_format = match matches
+        .opt_str("log-format")
+        .or_else(|| env::var("LOG_FORMAT").ok())
+        .map(|format| format.parse::<LogFormat>())
+    {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            exit(1)
+        }
+        None => LogFormat::Text,
+    };
+    init_logging(log_format);
+    let tracer_provider = init_tracing(
+        matches
+            .opt_str("otlp-endpoint")
+            .or_else(|| env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()),
+    );
     let host_port = format!(
         "{}:{}",
         matches
@@ -237,52 +969,1003 @@ async fn main() {
         matches.opt_get_default("port", 8080).unwrap()
     );
     let status_codes = !matches.opt_present("always-ok");
+    let config = match matches.opt_str("config") {
+        Some(path) => match Config::load(Path::new(&path)) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}: {}", path, e);
+                exit(1)
+            }
+        },
+        None => Config::default(),
+    };
+    let config = Arc::new(config);
+    let tls_config = config.tls.clone();
+    let proxy = match matches.opt_str("proxy").map(|url| Url::parse(&url)) {
+        Some(Ok(url)) => {
+            info!(
+                "Sending requests to Datadog through the proxy at {}",
+                url.host_str().unwrap_or_default()
+            );
+            Some(url)
+        }
+        Some(Err(e)) => {
+            error!("Invalid --proxy: {}", e);
+            exit(1)
+        }
+        None => None,
+    };
+    let root_certificates = match matches.opt_str("ca-file") {
+        Some(path) => match read_certificates(Path::new(&path)) {
+            Ok(certificates) => {
+                info!(
+                    "Trusting {} extra certificates from {}",
+                    certificates.len(),
+                    path
+                );
+                certificates
+            }
+            Err(e) => {
+                error!("{}: {}", path, e);
+                exit(1)
+            }
+        },
+        None => Vec::new(),
+    };
+    let client_options = ClientOptions {
+        connect_timeout: env_seconds("DATADOG_CONNECT_TIMEOUT_SECONDS", 5),
+        timeout: env_seconds("DATADOG_TIMEOUT_SECONDS", 10),
+        proxy,
+        no_proxy: env::var("NO_PROXY").or_else(|_| env::var("no_proxy")).ok(),
+        root_certificates,
+    };
+    let api_url = matches
+        .opt_str("api-url")
+        .or_else(|| env::var("DATADOG_API_URL").ok())
+        .unwrap_or_else(|| DEFAULT_API_URL.to_owned());
+    let client = match build_client(&client_options) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Could not create the HTTP client: {}", e);
+            exit(1)
+        }
+    };
+    let fixtures = matches.opt_strs("record");
+    if !fixtures.is_empty() {
+        let dir = matches
+            .opt_str("fixtures-dir")
+            .unwrap_or_else(|| "src/test_data/monitors".to_owned());
+        exit(record_fixtures(&client, &api_url, Path::new(&dir), &fixtures).await);
+    }
+    let lifespan = config.longest_ttl(*MAX_AGE_SECONDS);
+    let memory_cache = Arc::new(MemoryCache::with_lifespan(lifespan));
+    let cache: Arc<dyn MonitorCache> = match matches.opt_str("redis-url") {
+        Some(url) => match RedisCache::connect(&url, lifespan).await {
+            Ok(cache) => {
+                info!(
+                    "Sharing the monitor cache through Redis at {}",
+                    logging::redact(&url)
+                );
+                Arc::new(cache)
+            }
+            Err(e) => {
+                error!(
+                    "Could not connect to Redis at {}: {}",
+                    logging::redact(&url),
+                    e
+                );
+                exit(1)
+            }
+        },
+        None => memory_cache.clone(),
+    };
+    let history_size = match env::var("TRANSITION_HISTORY_SIZE") {
+        Ok(value) => value.parse::<usize>().unwrap_or(50),
+        Err(_) => 50,
+    };
+    let history_badges = match env::var("TRANSITION_HISTORY_BADGES") {
+        Ok(value) => value.parse::<usize>().unwrap_or(10_000),
+        Err(_) => 10_000,
+    };
+    let service = Arc::new(BadgeService::new(
+        Arc::new(DatadogSource::new(client, api_url)),
+        cache,
+        CacheTtls {
+            rules: config.cache.clone(),
+            default: *MAX_AGE_SECONDS,
+            longest: lifespan,
+        },
+        TransitionHistory::new(history_size, history_badges),
+    ));
+    let cache_file = matches.opt_str("cache-file").map(PathBuf::from);
+    if cache_file.is_some() && matches.opt_present("redis-url") {
+        warn!("Ignoring --cache-file as Redis persists the shared monitor cache");
+    }
+    let cache_file = cache_file.filter(|_| !matches.opt_present("redis-url"));
+    if let Some(path) = &cache_file {
+        let monitors = match persist::load(path) {
+            Ok(monitors) => monitors,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Ignoring monitor cache {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        let restored = memory_cache.restore(monitors, *CACHE_FILE_MAX_AGE_SECONDS);
+        info!("Restored {} monitors from {}", restored, path.display());
+        let path = path.clone();
+        let memory_cache = memory_cache.clone();
+        tokio::spawn(async move {
+            loop {
+                time::delay_for(Duration::from_secs(30)).await;
+                save_monitor_cache(&memory_cache, &path).await;
+            }
+        });
+    }
+    let tls = match (matches.opt_str("tls-cert"), matches.opt_str("tls-key")) {
+        (Some(cert), Some(key)) => Some(TlsConfig {
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+            redirect_port: None,
+        }),
+        (None, None) => tls_config,
+        _ => {
+            error!("--tls-cert and --tls-key must be given together");
+            exit(1)
+        }
+    };
+    // the option applies to TLS from either the options or the configuration file
+    let tls = match (tls, matches.opt_get("redirect-port").unwrap_or(None)) {
+        (Some(tls), Some(port)) => Some(TlsConfig {
+            redirect_port: Some(port),
+            ..tls
+        }),
+        (None, Some(_)) => {
+            error!("--redirect-port needs TLS, from --tls-cert and --tls-key or the [tls] section of the configuration file");
+            exit(1)
+        }
+        (tls, None) => tls,
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
 
-    let log = warp::log("access");
-    let monitor_badge = warp::path("accounts")
-        .and(warp::path::param())
-        .and(warp::path("monitors"))
-        .and(warp::path::param())
-        .and(warp::query::query())
-        .and_then(move |account, id, query| get_monitor_badge(status_codes, account, id, query));
-    let fallback = warp::any().map(|| {
-        Response::builder()
-            .status(404)
-            .header("Content-Type", "text/html; charset=UTF-8")
-            .body(include_str!("404.html"))
+    let root = matches
+        .opt_default("context-root", "/")
+        .unwrap_or_else(|| "/".to_owned());
+    let segments: Vec<String> = root
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let prefix: String = segments.iter().map(|s| format!("/{}", s)).collect();
+    info!("Listening for connections on {}{}/", host_port, prefix);
+
+    let routes = routes(
+        service,
+        config,
+        RouteOptions {
+            status_codes,
+            segments,
+            scheme,
+            trust_forwarded: matches.opt_present("trust-forwarded"),
+            webhook_secret: env::var("DATADOG_WEBHOOK_SECRET").ok().map(Arc::new),
+            live_badges: match env::var("LIVE_BADGE_POLLERS") {
+                Ok(value) => value.parse::<usize>().unwrap_or(1000),
+                Err(_) => 1000,
+            },
+        },
+    );
+    let addr = host_port
+        .as_str()
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+
+    let drain_seconds = matches
+        .opt_get_default("shutdown-timeout", 30)
+        .unwrap_or(30);
+    let shutdown = shutdown_signal().shared();
+    let server = match tls {
+        Some(tls) => serve_tls(routes, addr, tls, shutdown.clone()),
+        None => {
+            let connections = accept_connections(listen(addr), shutdown.clone(), |stream| {
+                future::ready(Some(stream))
+            });
+            serve(routes, connections, shutdown.clone())
+        }
+    };
+    let deadline = shutdown.then(|_| time::delay_for(Duration::from_secs(drain_seconds)));
+    if let Either::Right(_) = future::select(server, deadline.boxed()).await {
+        warn!(
+            "Gave up waiting for in-flight requests after {} seconds",
+            drain_seconds
+        );
+    }
+    if let Some(path) = &cache_file {
+        save_monitor_cache(&memory_cache, path).await;
+    }
+    stop_tracing(tracer_provider);
+    info!("Stopped");
+}
+
+/// What exports the traces, which needs stopping to send the spans that are still waiting
+#[cfg(feature = "otlp")]
+type TracerProvider = opentelemetry_sdk::trace::SdkTracerProvider;
+
+#[cfg(not(feature = "otlp"))]
+type TracerProvider = Infallible;
+
+/// Exports traces to the OpenTelemetry collector at the endpoint, if there is one
+#[cfg(feature = "otlp")]
+fn init_tracing(endpoint: Option<String>) -> Option<TracerProvider> {
+    match endpoint {
+        Some(endpoint) => match telemetry::tracer_provider(&endpoint) {
+            Ok(provider) => {
+                info!("Exporting traces to {}", logging::redact(&endpoint));
+                if let Err(e) =
+                    tracing::subscriber::set_global_default(telemetry::subscriber(&provider))
+                {
+                    warn!("Could not export traces: {}", e);
+                }
+                Some(provider)
+            }
+            Err(e) => {
+                error!("Invalid --otlp-endpoint: {}", e);
+                exit(1)
+            }
+        },
+        None => {
+            disable_tracing();
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+fn init_tracing(endpoint: Option<String>) -> Option<TracerProvider> {
+    if endpoint.is_some() {
+        warn!("Not exporting traces, as this build does not have the otlp feature");
+    }
+    disable_tracing();
+    None
+}
+
+/// Keeps the spans out of the log, where the HTTP libraries would otherwise have tracing write
+/// every one of them
+fn disable_tracing() {
+    let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default());
+}
+
+#[cfg(feature = "otlp")]
+fn stop_tracing(provider: Option<TracerProvider>) {
+    if let Some(provider) = provider {
+        // send the spans that are still waiting in the batch
+        if let Err(e) = provider.shutdown() {
+            warn!("Could not export the remaining traces: {}", e);
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+fn stop_tracing(_provider: Option<TracerProvider>) {}
+
+/// Writes the access log record of a request, in the same format as `warp::log`
+fn access_log(info: warp::log::Info) {
+    logging::record(|log| {
+        log.method = Some(info.method().to_string());
+        log.path = Some(info.path().to_owned());
+        log.status = Some(info.status().as_u16());
+        log.latency_ms = Some(logging::millis(info.elapsed()));
+        log.user_agent = info.user_agent().map(str::to_owned);
     });
+    let remote_addr = logging::current().and_then(|log| log.remote_addr);
     info!(
-        "Listening for connections on {}/{}",
-        host_port,
-        matches
-            .opt_default("context-root", "/")
-            .unwrap_or_else(|| "/".to_owned())
+        target: "access",
+        "{} \"{} {} {:?}\" {} \"{}\" \"{}\" {:?}",
+        remote_addr.map_or_else(|| "-".to_owned(), |addr| addr.to_string()),
+        info.method(),
+        info.path(),
+        info.version(),
+        info.status().as_u16(),
+        info.referer().unwrap_or("-"),
+        info.user_agent().unwrap_or("-"),
+        info.elapsed(),
     );
+}
 
-    let root = matches
-        .opt_default("context-root", "/")
-        .unwrap_or_else(|| "/".to_owned());
-    if root != "/" && root != "" {
-        warp::serve(warp::path(root).and(monitor_badge).or(fallback).with(log))
-            .run(
-                host_port
-                    .as_str()
-                    .to_socket_addrs()
-                    .unwrap()
-                    .next()
-                    .unwrap(),
-            )
+/// Resolves when the process is asked to stop
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                future::select(signal::ctrl_c().boxed(), terminate.recv().boxed()).await;
+            }
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {}", e);
+                let _ = signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+    info!("Stopping, waiting for in-flight requests");
+}
+
+/// Serves the routes over HTTPS until `shutdown` resolves and the in-flight requests complete
+fn serve_tls(
+    routes: impl Filter<Extract = (impl warp::Reply,), Error = Rejection>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    addr: SocketAddr,
+    tls: TlsConfig,
+    shutdown: Shared<impl Future<Output = ()> + Send + 'static>,
+) -> BoxFuture<'static, ()> {
+    let certificate = match ReloadingCertificate::load(&tls.cert, &tls.key) {
+        Ok(certificate) => Arc::new(certificate),
+        Err(e) => {
+            error!("{} and {}: {}", tls.cert.display(), tls.key.display(), e);
+            exit(1)
+        }
+    };
+    {
+        let certificate = certificate.clone();
+        tokio::spawn(async move {
+            loop {
+                time::delay_for(Duration::from_secs(30)).await;
+                match certificate.reload_if_changed() {
+                    Ok(true) => info!("Reloaded the TLS certificate"),
+                    Ok(false) => {}
+                    Err(e) => warn!("Could not reload the TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+    if let Some(port) = tls.redirect_port {
+        let https_port = addr.port();
+        let redirect = path_and_query()
+            .and(warp::header::optional::<String>("Host"))
+            .map(move |path_and_query: String, host: Option<String>| {
+                let host = host.unwrap_or_else(|| "localhost".to_owned());
+                Response::builder()
+                    .status(308)
+                    .header(
+                        "Location",
+                        https_location(&host, https_port, &path_and_query),
+                    )
+                    .body(String::new())
+            });
+        let redirect_addr = SocketAddr::new(addr.ip(), port);
+        info!("Redirecting plain HTTP on port {} to HTTPS", port);
+        let connections = accept_connections(listen(redirect_addr), shutdown.clone(), |stream| {
+            future::ready(Some(stream))
+        });
+        tokio::spawn(serve(
+            redirect.with(warp::log::custom(access_log)),
+            connections,
+            shutdown.clone(),
+        ));
+    }
+    let acceptor = TlsAcceptor::from(Arc::new(certificate.server_config()));
+    let connections = accept_connections(listen(addr), shutdown.clone(), move |stream| {
+        let acceptor = acceptor.clone();
+        async move {
+            match time::timeout(Duration::from_secs(10), acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => Some(stream),
+                Ok(Err(e)) => {
+                    debug!("TLS handshake failed: {}", e);
+                    None
+                }
+                Err(_) => {
+                    debug!("TLS handshake timed out");
+                    None
+                }
+            }
+        }
+    });
+    serve(routes, connections, shutdown)
+}
+
+fn listen(addr: SocketAddr) -> TcpListener {
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std);
+    match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen on {}: {}", addr, e);
+            exit(1)
+        }
+    }
+}
+
+/// Accepts connections until `shutdown` resolves, passing each through `handshake` separately so
+/// that a slow client cannot hold up the others
+fn accept_connections<C, H, F>(
+    mut listener: TcpListener,
+    shutdown: Shared<impl Future<Output = ()> + Send + 'static>,
+    handshake: H,
+) -> mpsc::Receiver<io::Result<C>>
+where
+    C: Send + 'static,
+    H: Fn(TcpStream) -> F + Send + 'static,
+    F: Future<Output = Option<C>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let accepted = match future::select(listener.accept().boxed(), shutdown.clone()).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => break,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Could not accept connection: {}", e);
+                    time::delay_for(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let _ = stream.set_nodelay(true);
+            let connection = handshake(stream);
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                if let Some(connection) = connection.await {
+                    let _ = tx.send(Ok(connection)).await;
+                }
+            });
+        }
+    });
+    rx
+}
+
+/// A connection that requests are served over
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for TcpStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+impl Connection for server::TlsStream<TcpStream> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+}
+
+/// Serves the routes over the connections until `shutdown` resolves and the in-flight requests
+/// complete, handling each request with its own request log
+fn serve<C: Connection>(
+    routes: impl Filter<Extract = (impl warp::Reply,), Error = Rejection>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    connections: mpsc::Receiver<io::Result<C>>,
+    shutdown: Shared<impl Future<Output = ()> + Send + 'static>,
+) -> BoxFuture<'static, ()> {
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |connection: &C| {
+        let remote_addr = connection.remote_addr();
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: hyper::Request<hyper::Body>| {
+                let header = |name| {
+                    request
+                        .headers()
+                        .get(name)
+                        .and_then(|value: &HeaderValue| value.to_str().ok())
+                };
+                let id = logging::request_id(header("X-Request-Id"));
+                let span = info_span!(
+                    "request",
+                    otel.kind = "server",
+                    request_id = %id,
+                    http.method = %request.method(),
+                    http.target = %request.uri().path(),
+                    http.status_code = field::Empty,
+                );
+                // continue the trace of the caller, if the spans are being exported
+                #[cfg(feature = "otlp")]
+                let _ = span.set_parent(telemetry::parent_context(header("traceparent")));
+                let response = service.clone().call(request).instrument(span.clone());
+                logging::scope(RequestLog::new(id.clone(), remote_addr), response).map_ok(
+                    move |mut response| {
+                        span.record("http.status_code", response.status().as_u16());
+                        if let Ok(value) = HeaderValue::from_str(&id) {
+                            response.headers_mut().insert("X-Request-Id", value);
+                        }
+                        response
+                    },
+                )
+            }))
+        }
+    });
+    hyper::Server::builder(accept::from_stream(connections))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .map(|result| {
+            if let Err(e) = result {
+                error!("server error: {}", e);
+            }
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::json;
+    use warp::http::Response;
+    use warp::hyper::body::Bytes;
+    use warp::{Filter, Rejection, Reply};
+
+    use datadog_badges::badge::{COLOR_DANGER, COLOR_OTHER, COLOR_SUCCESS, COLOR_WARNING};
+    use datadog_badges::cache::MemoryCache;
+    use datadog_badges::config::Config;
+    use datadog_badges::datadog::{build_client, ClientOptions};
+    use datadog_badges::history::TransitionHistory;
+    use datadog_badges::service::{BadgeService, CacheTtls};
+    use datadog_badges::source::DatadogSource;
+
+    use crate::mock_datadog::{MockDatadog, MockResponse};
+    use crate::{routes, RouteOptions};
+
+    const WEBHOOK_SECRET: &str = "s3cret";
+
+    /// The routes of a server that fetches monitors from the mock, giving up after a second
+    fn server(
+        mock: &MockDatadog,
+        status_codes: bool,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static
+    {
+        server_with_config(mock, status_codes, Config::default())
+    }
+
+    fn server_with_config(
+        mock: &MockDatadog,
+        status_codes: bool,
+        config: Config,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static
+    {
+        server_with_options(
+            mock,
+            config,
+            RouteOptions {
+                status_codes,
+                segments: Vec::new(),
+                scheme: "http",
+                trust_forwarded: false,
+                webhook_secret: Some(Arc::new(WEBHOOK_SECRET.to_owned())),
+                live_badges: 10,
+            },
+        )
+    }
+
+    fn server_with_options(
+        mock: &MockDatadog,
+        config: Config,
+        options: RouteOptions,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static
+    {
+        env::set_var("MOCK_DATADOG_API_KEY", "api");
+        env::set_var("MOCK_DATADOG_APP_KEY", "app");
+        let client = build_client(&ClientOptions {
+            timeout: Duration::from_secs(1),
+            ..ClientOptions::default()
+        })
+        .unwrap();
+        let service = BadgeService::new(
+            Arc::new(DatadogSource::new(client, mock.url())),
+            Arc::new(MemoryCache::with_lifespan(60)),
+            CacheTtls {
+                default: 15,
+                longest: 60,
+                ..CacheTtls::default()
+            },
+            TransitionHistory::new(10, 100),
+        );
+        routes(Arc::new(service), Arc::new(config), options)
+    }
+
+    fn monitor(state: &str) -> MockResponse {
+        MockResponse::json(&json!({
+            "id": 1,
+            "type": "metric alert",
+            "overall_state": state,
+            "overall_state_modified": "2020-01-26T00:53:20+00:00",
+            "options": { "silenced": {} },
+            "tags": ["env:prod"],
+        }))
+    }
+
+    fn header<'a>(response: &'a Response<Bytes>, name: &str) -> &'a str {
+        response
+            .headers()
+            .get(name)
+            .map_or("", |value| value.to_str().unwrap())
+    }
+
+    fn body(response: &Response<Bytes>) -> &str {
+        std::str::from_utf8(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_monitor_badge() {
+        let mock = MockDatadog::start();
+        mock.monitor("1", monitor("OK"));
+        let server = server(&mock, true);
+
+        let response = warp::test::request()
+            .path("/accounts/mock/monitors/1")
+            .reply(&server)
             .await;
-    } else {
-        warp::serve(monitor_badge.or(fallback).with(log))
-            .run(
-                host_port
-                    .as_str()
-                    .to_socket_addrs()
-                    .unwrap()
-                    .next()
-                    .unwrap(),
-            )
+        assert_eq!(response.status(), 200);
+        assert_eq!(header(&response, "Content-Type"), "image/svg+xml");
+        assert_eq!(header(&response, "Cache-Control"), "public,max-age=15");
+        assert_eq!(
+            header(&response, "Last-Modified"),
+            "Sun, 26 Jan 2020 00:53:20 GMT"
+        );
+        assert!(body(&response).contains(">Ok</text>"));
+        assert!(body(&response).contains(COLOR_SUCCESS));
+
+        // revalidating is served from the cache without asking Datadog again
+        let etag = header(&response, "ETag").to_owned();
+        let response = warp::test::request()
+            .path("/accounts/mock/monitors/1")
+            .header("If-None-Match", etag)
+            .reply(&server)
+            .await;
+        assert_eq!(response.status(), 304);
+        assert_eq!(body(&response), "");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/api/v1/monitor/1");
+        assert_eq!(requests[0].query, "group_states=all");
+        assert_eq!(requests[0].api_key.as_deref(), Some("api"));
+        assert_eq!(requests[0].app_key.as_deref(), Some("app"));
+    }
+
+    #[tokio::test]
+    async fn test_error_badges() {
+        let mock = MockDatadog::start();
+        mock.monitor("403", MockResponse::error(403));
+        mock.monitor(
+            "429",
+            MockResponse::error(429).with_header("X-RateLimit-Reset", "7"),
+        );
+        mock.monitor("500", MockResponse::error(500));
+        mock.monitor("garbage", MockResponse::garbage());
+        mock.monitor("slow", monitor("OK").with_delay(Duration::from_secs(3)));
+        let server = server(&mock, true);
+        for (path, status, text, color) in [
+            ("mock/monitors/404", 404, "Not found", COLOR_OTHER),
+            ("mock/monitors/403", 403, "Access denied", COLOR_WARNING),
+            ("mock/monitors/500", 500, "Datadog HTTP/500", COLOR_WARNING),
+            (
+                "mock/monitors/garbage",
+                502,
+                "Invalid response",
+                COLOR_WARNING,
+            ),
+            ("mock/monitors/slow", 504, "Timeout", COLOR_WARNING),
+            ("mock/monitors/429", 429, "Rate limited", COLOR_WARNING),
+            // the account is backed off, so its other monitors are not fetched either
+            ("mock/monitors/1", 429, "Rate limited", COLOR_WARNING),
+            (
+                "unset/monitors/1",
+                404,
+                "Unconfigured account: unset",
+                COLOR_OTHER,
+            ),
+        ] {
+            let response = warp::test::request()
+                .path(&format!("/accounts/{}", path))
+                .reply(&server)
+                .await;
+            assert_eq!(response.status(), status, "{}", path);
+            assert_eq!(header(&response, "Content-Type"), "image/svg+xml");
+            assert!(body(&response).contains(text), "{}", path);
+            assert!(body(&response).contains(color), "{}", path);
+        }
+        assert!(!mock
+            .requests()
+            .iter()
+            .any(|request| request.path == "/api/v1/monitor/1"));
+    }
+
+    #[tokio::test]
+    async fn test_always_ok() {
+        let mock = MockDatadog::start();
+        mock.monitor("1", MockResponse::error(500));
+        let response = warp::test::request()
+            .path("/accounts/mock/monitors/1")
+            .reply(&server(&mock, false))
             .await;
+        assert_eq!(response.status(), 200);
+        assert!(body(&response).contains("Datadog HTTP/500"));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_evicts() {
+        let mock = MockDatadog::start();
+        mock.monitor("1", monitor("OK"));
+        let server = server(&mock, true);
+        let badge = || {
+            warp::test::request()
+                .path("/accounts/mock/monitors/1")
+                .reply(&server)
+        };
+        assert!(body(&badge().await).contains(">Ok</text>"));
+        mock.monitor("1", monitor("Alert"));
+        assert!(body(&badge().await).contains(">Ok</text>"));
+
+        let webhook = |secret: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/webhooks/datadog")
+                .header("X-Webhook-Secret", secret)
+                .body(r#"{"account": "mock", "monitor_id": "1"}"#)
+                .reply(&server)
+        };
+        assert_eq!(webhook("wrong").await.status(), 401);
+        assert_eq!(webhook(WEBHOOK_SECRET).await.status(), 204);
+        let response = badge().await;
+        assert!(body(&response).contains(">Alert</text>"));
+        assert!(body(&response).contains(COLOR_DANGER));
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers() {
+        let mock = MockDatadog::start();
+        let embed = |trust_forwarded| {
+            let server = server_with_options(
+                &mock,
+                Config::default(),
+                RouteOptions {
+                    status_codes: true,
+                    segments: Vec::new(),
+                    scheme: "http",
+                    trust_forwarded,
+                    webhook_secret: None,
+                    live_badges: 10,
+                },
+            );
+            async move {
+                let res = warp::test::request()
+                    .path("/accounts/mock/monitors/1/embed")
+                    .header("Host", "badges:8080")
+                    .header("X-Forwarded-Proto", "https")
+                    .header("X-Forwarded-Host", "elsewhere.example.com")
+                    .reply(&server)
+                    .await;
+                String::from_utf8(res.body().to_vec()).unwrap()
+            }
+        };
+        // a client cannot choose the URLs in the snippets unless the proxy is trusted
+        let page = embed(false).await;
+        assert!(page.contains("http://badges:8080/accounts/mock/monitors/1"));
+        assert!(!page.contains("elsewhere.example.com"));
+        let page = embed(true).await;
+        assert!(page.contains("https://elsewhere.example.com/accounts/mock/monitors/1"));
+    }
+
+    #[tokio::test]
+    async fn test_live_badge_routes() {
+        let mock = MockDatadog::start();
+        let config = Config::parse(
+            r#"
+[aliases.checkout]
+account = "mock"
+monitor = "1"
+"#,
+        )
+        .unwrap();
+        // with no room for another poller, the streams that exist are refused rather than missing
+        let server = server_with_options(
+            &mock,
+            config,
+            RouteOptions {
+                status_codes: true,
+                segments: Vec::new(),
+                scheme: "http",
+                trust_forwarded: false,
+                webhook_secret: None,
+                live_badges: 0,
+            },
+        );
+        for (path, status) in [
+            ("/accounts/mock/monitors/1/events", 503),
+            ("/badges/checkout/events", 503),
+            ("/badges/missing/events", 404),
+        ] {
+            let response = warp::test::request().path(path).reply(&server).await;
+            assert_eq!(response.status(), status, "{}", path);
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_alias_targets() {
+        let mock = MockDatadog::start();
+        mock.respond(
+            "/api/v1/slo/abc",
+            MockResponse::json(&json!({
+                "data": {
+                    "id": "abc",
+                    "tags": ["team:checkout"],
+                    "thresholds": [{ "timeframe": "30d", "target": 99.9, "warning": 99.95 }],
+                },
+            })),
+        );
+        mock.respond(
+            "/api/v1/slo/abc/history",
+            MockResponse::json(&json!({ "data": { "overall": { "sli_value": 99.92 } } })),
+        );
+        mock.respond(
+            "/api/v1/monitor",
+            MockResponse::json(&json!([
+                { "id": 1, "overall_state": "OK", "tags": ["env:prod"] },
+                { "id": 2, "overall_state": "Alert", "tags": ["env:staging"] },
+            ])),
+        );
+        let config = Config::parse(
+            r#"
+[aliases.checkout-slo]
+account = "mock"
+slo = "abc"
+
+[aliases.checkout-all]
+account = "mock"
+tags = "team:checkout"
+
+[aliases.checkout-prod]
+account = "mock"
+tags = "team:checkout"
+query = { q = "env:prod" }
+"#,
+        )
+        .unwrap();
+        let server = server_with_config(&mock, true, config);
+        for (name, text) in [
+            ("checkout-slo", ">Warn</text>"),
+            ("checkout-all", ">Alert</text>"),
+            ("checkout-prod", ">Ok</text>"),
+        ] {
+            let response = warp::test::request()
+                .path(&format!("/badges/{}", name))
+                .reply(&server)
+                .await;
+            assert_eq!(response.status(), 200, "{}", name);
+            assert!(body(&response).contains(text), "{}", name);
+        }
+        let requests = mock.requests();
+        let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+        // both tag query aliases share one fetch
+        assert_eq!(
+            paths,
+            [
+                "/api/v1/slo/abc",
+                "/api/v1/slo/abc/history",
+                "/api/v1/monitor"
+            ]
+        );
+        assert_eq!(
+            requests[2].query,
+            "monitor_tags=team%3Acheckout&group_states=all"
+        );
+
+        // only the aliases can show SLOs and tag queries, whatever the client asks for
+        for path in [
+            "/accounts/mock/monitors/slo:abc",
+            "/accounts/mock/monitors/tags:env:prod",
+            "/accounts/mock/monitors/tags%3Aenv%3Aprod?g=true",
+            "/accounts/mock/monitors/slo:abc/feed",
+            "/accounts/mock/monitors/slo:abc/embed",
+            "/accounts/mock/monitors/tags:env:prod/events",
+        ] {
+            let response = warp::test::request().path(path).reply(&server).await;
+            assert_eq!(response.status(), 404, "{}", path);
+        }
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_status_page() {
+        let mock = MockDatadog::start();
+        mock.monitor("1", monitor("OK"));
+        mock.monitor("2", monitor("Alert"));
+        let config = Config::parse(
+            r#"
+[aliases.checkout-prod]
+account = "mock"
+monitor = "1"
+
+[status_page]
+title = "Mock Team"
+
+[[status_page.sections]]
+title = "Checkout"
+badges = [
+    { name = "Production", alias = "checkout-prod" },
+    { name = "Staging", account = "mock", monitor = "2", ttl = 30 },
+]
+"#,
+        )
+        .unwrap();
+        let server = server_with_config(&mock, true, config);
+        let response = warp::test::request().path("/").reply(&server).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            "text/html; charset=UTF-8"
+        );
+        let page = body(&response);
+        assert!(page.contains("<h2>Checkout</h2>"));
+        let production = page.find(">Production</a>").unwrap();
+        let staging = page.find(">Staging</a>").unwrap();
+        assert!(production < staging);
+        assert!(page[..production].contains("alt=\"Ok\""));
+        assert!(page[production..staging].contains("alt=\"Alert\""));
+        let mut paths: Vec<String> = mock.requests().into_iter().map(|r| r.path).collect();
+        paths.sort();
+        assert_eq!(paths, ["/api/v1/monitor/1", "/api/v1/monitor/2"]);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_evicts_aliases() {
+        let mock = MockDatadog::start();
+        let tagged = |state: &str| {
+            MockResponse::json(&json!([
+                { "id": 1, "overall_state": "OK", "tags": ["env:prod"] },
+                { "id": 2, "overall_state": state, "tags": ["env:staging"] },
+            ]))
+        };
+        mock.respond("/api/v1/monitor", tagged("OK"));
+        let config = Config::parse(
+            r#"
+[aliases.checkout]
+account = "mock"
+tags = "team:checkout"
+"#,
+        )
+        .unwrap();
+        let server = server_with_config(&mock, true, config);
+        let badge = || {
+            warp::test::request()
+                .path("/badges/checkout")
+                .reply(&server)
+        };
+        let webhook = |id: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/webhooks/datadog")
+                .header("X-Webhook-Secret", WEBHOOK_SECRET)
+                .body(json!({ "account": "mock", "monitor_id": id }).to_string())
+                .reply(&server)
+        };
+        assert!(body(&badge().await).contains(">Ok</text>"));
+        mock.respond("/api/v1/monitor", tagged("Alert"));
+        assert_eq!(webhook("3").await.status(), 204);
+        assert!(body(&badge().await).contains(">Ok</text>"));
+        assert_eq!(mock.requests().len(), 1);
+
+        // a webhook for any monitor of the tag query evicts the alias badge
+        assert_eq!(webhook("2").await.status(), 204);
+        assert!(body(&badge().await).contains(">Alert</text>"));
+        assert_eq!(mock.requests().len(), 2);
     }
 }
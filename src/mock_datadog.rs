@@ -0,0 +1,154 @@
+//! An in-process stand-in for the Datadog API, so that tests can exercise the whole HTTP path
+//! from a badge request to the response from Datadog
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::time;
+use warp::filters::path::FullPath;
+use warp::http::Response;
+use warp::Filter;
+
+/// What the mock API answers a request with
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    delay: Duration,
+}
+
+impl MockResponse {
+    /// A successful response with the JSON body
+    pub fn json(body: &Value) -> MockResponse {
+        MockResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_owned(), "application/json".to_owned())],
+            body: body.to_string(),
+            delay: Duration::from_secs(0),
+        }
+    }
+
+    /// An error response with the body Datadog sends with its errors
+    pub fn error(status: u16) -> MockResponse {
+        MockResponse {
+            status,
+            ..MockResponse::json(&json!({ "errors": [format!("HTTP/{}", status)] }))
+        }
+    }
+
+    /// A successful response with a body that is not JSON, as from a misbehaving proxy
+    pub fn garbage() -> MockResponse {
+        MockResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_owned(), "text/html".to_owned())],
+            body: "<html>Welcome to nginx!</html>".to_owned(),
+            delay: Duration::from_secs(0),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Waits before answering, to test timeouts
+    pub fn with_delay(mut self, delay: Duration) -> MockResponse {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A request that the mock API received
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub path: String,
+    pub query: String,
+    pub api_key: Option<String>,
+    pub app_key: Option<String>,
+}
+
+/// A mock of the Datadog API listening on a local port; paths without a response are not found
+/// and requests without both keys are forbidden, as with Datadog
+pub struct MockDatadog {
+    url: String,
+    responses: Arc<Mutex<HashMap<String, MockResponse>>>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockDatadog {
+    /// Starts serving on an ephemeral port of the loopback interface, from within a runtime
+    pub fn start() -> MockDatadog {
+        let responses: Arc<Mutex<HashMap<String, MockResponse>>> = Arc::default();
+        let requests: Arc<Mutex<Vec<MockRequest>>> = Arc::default();
+        let api = {
+            let responses = responses.clone();
+            let requests = requests.clone();
+            warp::path::full()
+                .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+                .and(warp::header::optional::<String>("DD-API-KEY"))
+                .and(warp::header::optional::<String>("DD-APPLICATION-KEY"))
+                .and_then(move |path: FullPath, query, api_key, app_key| {
+                    let request = MockRequest {
+                        path: path.as_str().to_owned(),
+                        query,
+                        api_key,
+                        app_key,
+                    };
+                    requests.lock().unwrap().push(request.clone());
+                    let response = if request.api_key.is_none() || request.app_key.is_none() {
+                        MockResponse::error(403)
+                    } else {
+                        responses
+                            .lock()
+                            .unwrap()
+                            .get(&request.path)
+                            .cloned()
+                            .unwrap_or_else(|| MockResponse::error(404))
+                    };
+                    async move {
+                        time::delay_for(response.delay).await;
+                        let mut builder = Response::builder().status(response.status);
+                        for (name, value) in &response.headers {
+                            builder = builder.header(name.as_str(), value.as_str());
+                        }
+                        Ok::<_, Infallible>(builder.body(response.body))
+                    }
+                })
+        };
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        MockDatadog {
+            url: format!("http://{}", addr),
+            responses,
+            requests,
+        }
+    }
+
+    /// The base URL of the mock, to use in place of `DEFAULT_API_URL`
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Answers requests for the path, e.g. `/api/v1/slo/{id}` or
+    /// `/api/v1/synthetics/tests/{public_id}`, with the response from now on
+    pub fn respond(&self, path: &str, response: MockResponse) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), response);
+    }
+
+    /// Answers requests for the monitor with the response from now on
+    pub fn monitor(&self, id: &str, response: MockResponse) {
+        self.respond(&format!("/api/v1/monitor/{}", id), response);
+    }
+
+    /// The requests received so far, oldest first
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,239 @@
+extern crate chrono;
+extern crate percent_encoding;
+
+use chrono::{DateTime, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::badge::{escape_xml, Badge, BadgeOptions};
+use crate::config::StatusPage;
+
+const LAYOUT: &str = include_str!("page.html");
+
+/// Everything but the unreserved characters of RFC 3986
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Percent-encodes a path segment or query value, so that the URL it goes in cannot end the
+/// Markdown, wiki or other markup around it
+pub fn encode_uri_component(value: &str) -> String {
+    utf8_percent_encode(value, URI_COMPONENT).to_string()
+}
+
+/// Decodes a path segment as received in the request
+pub fn decode_uri_component(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+/// Substitutes the `{{name}}` placeholders of the template in a single pass, so that values
+/// containing placeholders are never expanded themselves
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = &after[..end];
+                match values.iter().find(|(k, _)| *k == name) {
+                    Some((_, value)) => result.push_str(value),
+                    None => result.push_str(&rest[start..start + end + 4]),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                result.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Renders a page using the common layout, the `body` and `head` must already be escaped
+fn page(title: &str, class: &str, head: &str, body: &str) -> String {
+    render(
+        LAYOUT,
+        &[
+            ("title", &escape_xml(title)),
+            ("class", class),
+            ("head", head),
+            ("body", body),
+        ],
+    )
+}
+
+pub fn not_found() -> String {
+    page(
+        "Page Not Found",
+        "message",
+        "",
+        "<h1>Page Not Found</h1>\n<p>Sorry, but the page you were trying to view does not exist.</p>",
+    )
+}
+
+/// A badge to show on the status page
+pub struct StatusPageEntry {
+    /// The name displayed next to the badge
+    pub name: String,
+    /// Where the name links to
+    pub link: String,
+    pub options: BadgeOptions,
+    /// When the monitor entered its current status
+    pub since: Option<DateTime<Utc>>,
+}
+
+pub fn status_page(
+    page_config: &StatusPage,
+    sections: &[(String, Vec<StatusPageEntry>)],
+) -> String {
+    let mut body = format!("<h1>{}</h1>\n", escape_xml(&page_config.title));
+    for (title, entries) in sections {
+        body.push_str(&format!("<h2>{}</h2>\n<table>\n", escape_xml(title)));
+        for entry in entries {
+            body.push_str(&format!(
+                "<tr><td><img src=\"{}\" alt=\"{}\"></td><td><a href=\"{}\">{}</a></td><td>{}</td></tr>\n",
+                Badge::new(entry.options.clone()).to_svg_data_uri(),
+                escape_xml(&entry.options.status),
+                escape_xml(&entry.link),
+                escape_xml(&entry.name),
+                match entry.since {
+                    Some(since) => format!(
+                        "since <time datetime=\"{}\">{}</time>",
+                        since.to_rfc3339(),
+                        since.format("%Y-%m-%d %H:%M UTC")
+                    ),
+                    None => String::new(),
+                }
+            ));
+        }
+        body.push_str("</table>\n");
+    }
+    page(
+        &page_config.title,
+        "status",
+        &format!(
+            "    <meta http-equiv=\"refresh\" content=\"{}\">",
+            page_config.refresh
+        ),
+        &body,
+    )
+}
+
+/// The ready-to-copy markup for embedding a badge in each supported format
+pub fn embed_snippets(badge_url: &str, link: &str) -> Vec<(&'static str, String)> {
+    const ALT: &str = "Datadog monitor status";
+    vec![
+        ("Markdown", format!("[![{}]({})]({})", ALT, badge_url, link)),
+        (
+            "HTML",
+            format!(
+                "<a href=\"{}\"><img src=\"{}\" alt=\"{}\"></a>",
+                escape_xml(link),
+                escape_xml(badge_url),
+                ALT
+            ),
+        ),
+        (
+            "reStructuredText",
+            format!(
+                ".. image:: {}\n   :target: {}\n   :alt: {}",
+                badge_url, link, ALT
+            ),
+        ),
+        (
+            "AsciiDoc",
+            format!("image:{}[{},link=\"{}\"]", badge_url, ALT, link),
+        ),
+        ("Confluence wiki", format!("[!{}!|{}]", badge_url, link)),
+    ]
+}
+
+/// Renders the page that previews a badge and generates the snippets to embed it
+pub fn embed_page(title: &str, badge_url: &str, link: &str, filter: &str, groups: bool) -> String {
+    let mut body = format!(
+        r#"<h1>{}</h1>
+<form method="get">
+<p><label>Filter <input type="text" name="q" value="{}" placeholder="env:prod"></label>
+<label><input type="checkbox" name="g" value="true"{}> Group states</label>
+<button type="submit">Update</button></p>
+</form>
+<p><img src="{}" alt="Preview"></p>
+"#,
+        escape_xml(title),
+        escape_xml(filter),
+        if groups { " checked" } else { "" },
+        escape_xml(badge_url),
+    );
+    for (format, snippet) in embed_snippets(badge_url, link) {
+        body.push_str(&format!(
+            "<h2>{}</h2>\n<textarea readonly rows=\"{}\" onclick=\"this.select()\">{}</textarea>\n",
+            format,
+            snippet.lines().count(),
+            escape_xml(&snippet)
+        ));
+    }
+    page(title, "status", "", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pages::{
+        decode_uri_component, embed_snippets, encode_uri_component, not_found, render,
+    };
+
+    #[test]
+    fn test_embed_snippets() {
+        let snippets = embed_snippets(
+            "http://example.com/accounts/team/monitors/1?q=a&g=true",
+            "https://team.datadoghq.com/monitors/1",
+        );
+        assert_eq!(
+            snippets[0].1,
+            "[![Datadog monitor status](http://example.com/accounts/team/monitors/1?q=a&g=true)](https://team.datadoghq.com/monitors/1)"
+        );
+        assert_eq!(
+            snippets[1].1,
+            "<a href=\"https://team.datadoghq.com/monitors/1\"><img src=\"http://example.com/accounts/team/monitors/1?q=a&amp;g=true\" alt=\"Datadog monitor status\"></a>"
+        );
+        assert_eq!(
+            snippets[4].1,
+            "[!http://example.com/accounts/team/monitors/1?q=a&g=true!|https://team.datadoghq.com/monitors/1]"
+        );
+    }
+
+    #[test]
+    fn test_embed_snippets_encoded() {
+        let account = encode_uri_component("te)am]");
+        let id = decode_uri_component("1%60%7C%20x");
+        assert_eq!(id, "1`| x");
+        let id = encode_uri_component(&id);
+        assert_eq!(id, "1%60%7C%20x");
+        let snippets = embed_snippets(
+            &format!("http://example.com/accounts/{}/monitors/{}", account, id),
+            &format!("https://{}.datadoghq.com/monitors/{}", account, id),
+        );
+        assert_eq!(
+            snippets[0].1,
+            "[![Datadog monitor status](http://example.com/accounts/te%29am%5D/monitors/1%60%7C%20x)](https://te%29am%5D.datadoghq.com/monitors/1%60%7C%20x)"
+        );
+        assert_eq!(
+            snippets[4].1,
+            "[!http://example.com/accounts/te%29am%5D/monitors/1%60%7C%20x!|https://te%29am%5D.datadoghq.com/monitors/1%60%7C%20x]"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("<b>{{a}}</b>{{b}}{{c}}", &[("a", "{{b}}"), ("b", "x")]),
+            "<b>{{b}}</b>x{{c}}"
+        );
+        assert_eq!(render("{{a", &[("a", "x")]), "{{a");
+        assert!(not_found().contains("<title>Page Not Found</title>"));
+    }
+}
@@ -0,0 +1,123 @@
+extern crate serde;
+extern crate serde_json;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CachedMonitor;
+
+/// The version of the cache file format, files with any other version are ignored
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The last fetch of a monitor
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PersistedMonitor {
+    pub account: String,
+    pub id: String,
+    pub with_groups: bool,
+    #[serde(flatten)]
+    pub monitor: CachedMonitor,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    monitors: Vec<PersistedMonitor>,
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<PersistedMonitor>> {
+    let text = fs::read(path)?;
+    let file: CacheFile =
+        serde_json::from_slice(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if file.version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported cache file version {}, expected {}",
+                file.version, FORMAT_VERSION
+            ),
+        ));
+    }
+    Ok(file.monitors)
+}
+
+/// Writes the monitors to a temporary file next to `path` and then renames it over `path`, so
+/// that a crash or power loss part way through never leaves a truncated cache file behind
+pub fn save(path: &Path, monitors: Vec<PersistedMonitor>) -> io::Result<()> {
+    let file = CacheFile {
+        version: FORMAT_VERSION,
+        monitors,
+    };
+    let text = serde_json::to_vec(&file).map_err(io::Error::other)?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(&text)?;
+    // the contents must reach the disk before the rename, which may otherwise land first
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    sync_parent(path)
+}
+
+/// Flushes the rename to disk, where directories can be opened and synced
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use crate::cache::{CachedMonitor, Fetched};
+    use crate::datadog::{DatadogError, MonitorState};
+    use crate::persist::{load, save, PersistedMonitor};
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir().join(format!("datadog-badges-{}.json", std::process::id()));
+        let monitors = vec![
+            PersistedMonitor {
+                account: "team".to_owned(),
+                id: "1".to_owned(),
+                with_groups: false,
+                monitor: CachedMonitor {
+                    result: Fetched::Monitor(
+                        serde_json::from_str::<MonitorState>(include_str!("test_data/sample.json"))
+                            .unwrap(),
+                    ),
+                    fetched: 2_000,
+                },
+            },
+            PersistedMonitor {
+                account: "team".to_owned(),
+                id: "2".to_owned(),
+                with_groups: true,
+                monitor: CachedMonitor {
+                    result: Fetched::Failed(DatadogError::RateLimited {
+                        retry_after: Some(10),
+                    }),
+                    fetched: 2_000,
+                },
+            },
+        ];
+        save(&path, monitors.clone()).unwrap();
+        assert_eq!(load(&path).unwrap(), monitors);
+
+        fs::write(&path, r#"{"version":2,"monitors":[]}"#).unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,519 @@
+extern crate chrono;
+extern crate tracing;
+
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use tracing::{info_span, Instrument};
+
+use crate::badge::{BadgeOptions, COLOR_DANGER, COLOR_OTHER, COLOR_SUCCESS, COLOR_WARNING};
+use crate::cache::{CachedMonitor, Fetched, MonitorCache};
+use crate::config::CacheConfig;
+use crate::datadog::{DatadogError, MonitorStatus, Target};
+use crate::history::{history_key, HistoryKey, Transition, TransitionHistory};
+use crate::logging::{self, CacheOutcome};
+use crate::source::MonitorSource;
+
+/// How long to stop fetching the monitors of an account for when Datadog rate limits it without
+/// saying for how long
+const DEFAULT_BACKOFF_SECONDS: u64 = 10;
+
+/// How long a failure to fetch a monitor is cached for, as it may well be transient
+const FAILURE_TTL_SECONDS: u64 = 5;
+
+/// How long monitors are fresh for
+#[derive(Clone, Debug, Default)]
+pub struct CacheTtls {
+    /// The overrides of the TTL by account, tag and alias
+    pub rules: CacheConfig,
+    /// The TTL of monitors that no override applies to
+    pub default: u64,
+    /// The longest TTL the overrides allow, which is how long the cache keeps monitors for
+    pub longest: u64,
+}
+
+/// What a request asks of the freshness of the monitor, on top of the configured TTLs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Freshness {
+    /// The TTL of the alias that the badge was requested through
+    pub alias_ttl: Option<u64>,
+    /// The `max_age` query parameter, already within the configured bounds
+    pub max_age: Option<u64>,
+}
+
+/// The badge of a monitor and what the HTTP response serving it needs to know
+#[derive(Clone, Debug)]
+pub struct MonitorBadge {
+    pub options: BadgeOptions,
+    /// The HTTP status code that describes why the badge shows an error, otherwise 200
+    pub status_code: u16,
+    /// When the monitor entered its current status
+    pub since: Option<DateTime<Utc>>,
+    /// How many more seconds the badge stays fresh for
+    pub fresh_for: u64,
+}
+
+/// Turns the monitors of a source into badges, caching the monitors and remembering the
+/// transitions of each badge
+pub struct BadgeService {
+    source: Arc<dyn MonitorSource>,
+    cache: Arc<dyn MonitorCache>,
+    ttls: CacheTtls,
+    history: Mutex<TransitionHistory>,
+}
+
+impl BadgeService {
+    pub fn new(
+        source: Arc<dyn MonitorSource>,
+        cache: Arc<dyn MonitorCache>,
+        ttls: CacheTtls,
+        history: TransitionHistory,
+    ) -> BadgeService {
+        BadgeService {
+            source,
+            cache,
+            ttls,
+            history: Mutex::new(history),
+        }
+    }
+
+    /// The TTL of the monitor for a request, or of the failure to fetch it: as long as Datadog
+    /// asked to wait for when rate limiting, otherwise a few seconds at most
+    fn ttl(&self, account: &str, freshness: Freshness, monitor: &CachedMonitor) -> u64 {
+        match &monitor.result {
+            Fetched::Monitor(state) => freshness.max_age.unwrap_or_else(|| {
+                self.ttls
+                    .rules
+                    .ttl(self.ttls.default, account, freshness.alias_ttl, &state.tags)
+            }),
+            Fetched::Failed(DatadogError::RateLimited { retry_after }) => {
+                retry_after.unwrap_or(DEFAULT_BACKOFF_SECONDS)
+            }
+            Fetched::Failed(_) => min(
+                freshness.max_age.unwrap_or(self.ttls.default),
+                FAILURE_TTL_SECONDS,
+            ),
+        }
+    }
+
+    /// Bounds the `max_age` a request asks for by the configured minimum and the longest time the
+    /// cache keeps monitors
+    pub fn bound_max_age(&self, max_age: u64) -> u64 {
+        min(max(max_age, self.ttls.rules.min_max_age), self.ttls.longest)
+    }
+
+    /// Whether the badges of the account should never be cached by browsers and image proxies
+    pub fn no_cache(&self, account: &str) -> bool {
+        self.ttls
+            .rules
+            .no_cache_accounts
+            .iter()
+            .any(|a| a == account)
+    }
+
+    /// Fetches the target from the source, using the cached monitor if it is still fresh enough,
+    /// along with how many more seconds it stays fresh for
+    ///
+    /// While Datadog is rate limiting the account, on this or any replica sharing the cache, the
+    /// monitor is not fetched and the stale cached monitor is used if there is one.
+    pub async fn fetch_monitor(
+        &self,
+        account: &str,
+        target: &Target,
+        with_groups: bool,
+        freshness: Freshness,
+    ) -> (CachedMonitor, u64) {
+        let id = target.id();
+        logging::record(|log| {
+            log.account = Some(account.to_owned());
+            log.monitor_id = Some(id.clone());
+        });
+        // SLOs and tag queries are fetched the same either way, so share one cache entry
+        let with_groups = with_groups && matches!(target, Target::Monitor(_));
+        let key = (account.to_owned(), id.clone(), with_groups);
+        let cached = self
+            .cache
+            .get(&key)
+            .instrument(info_span!("monitor_cache.get"))
+            .await;
+        if let Some(monitor) = &cached {
+            let ttl = self.ttl(account, freshness, monitor);
+            let age = max(Utc::now().timestamp() - monitor.fetched, 0) as u64;
+            if age <= ttl {
+                logging::record(|log| log.cache = Some(CacheOutcome::Hit));
+                return (monitor.clone(), ttl - age);
+            }
+        }
+        if let Some(left) = self.cache.backing_off(account).await {
+            logging::record(|log| log.cache = Some(CacheOutcome::Backoff));
+            let monitor = cached.unwrap_or_else(|| {
+                CachedMonitor::new(Fetched::Failed(DatadogError::RateLimited {
+                    retry_after: Some(left),
+                }))
+            });
+            return (monitor, left);
+        }
+        logging::record(|log| log.cache = Some(CacheOutcome::Miss));
+        let result = self.source.get_monitor(account, target, with_groups).await;
+        if let Err(e) = &result {
+            warn!("Could not fetch monitor {} of {}: {}", id, account, e);
+        }
+        if let Err(DatadogError::RateLimited { retry_after }) = &result {
+            let seconds = retry_after.unwrap_or(DEFAULT_BACKOFF_SECONDS);
+            self.cache.back_off(account, seconds).await;
+        }
+        let monitor = CachedMonitor::new(match result {
+            Ok(state) => Fetched::Monitor(state),
+            Err(e) => Fetched::Failed(e),
+        });
+        // the account cannot become configured while running, but telling so is cheap enough
+        // not to take a cache entry
+        if !matches!(monitor.result, Fetched::Failed(DatadogError::Config { .. })) {
+            self.cache.set(key, monitor.clone()).await;
+        }
+        let ttl = self.ttl(account, freshness, &monitor);
+        (monitor, ttl)
+    }
+
+    /// Computes the badge for the target from its cached state, so every query shares one fetch
+    pub async fn monitor_badge(
+        &self,
+        account: &str,
+        target: &Target,
+        query: &BTreeMap<String, String>,
+        freshness: Freshness,
+    ) -> MonitorBadge {
+        let (monitor, fresh_for) = self
+            .fetch_monitor(account, target, query.contains_key("g"), freshness)
+            .await;
+        match monitor.result {
+            Fetched::Failed(e) => MonitorBadge {
+                options: BadgeOptions {
+                    status: e.badge_text(),
+                    color: e.badge_color().to_owned(),
+                    ..BadgeOptions::default()
+                },
+                status_code: e.status_code(),
+                since: None,
+                fresh_for,
+            },
+            Fetched::Monitor(value) => {
+                let filter = query.get("q").map(String::as_ref);
+                let (status, since) = value.status(filter);
+                let groups = value.group_statuses(filter).unwrap_or_default();
+                {
+                    let id = target.id();
+                    let key = history_key(account, &id, query);
+                    let _span = info_span!("transition_history.lock").entered();
+                    let mut history = self.history.lock().unwrap();
+                    let at = since.unwrap_or_else(Utc::now);
+                    for transition in history.record(&key, status, &groups, at) {
+                        match &transition.group {
+                            Some(group) => info!(
+                                "Group {} of monitor {} of {} changed from {} to {}",
+                                group, id, account, transition.from, transition.to
+                            ),
+                            None => info!(
+                                "Monitor {} of {} changed from {} to {}",
+                                id, account, transition.from, transition.to
+                            ),
+                        }
+                    }
+                }
+                MonitorBadge {
+                    options: BadgeOptions {
+                        duration: since.map(|v| Utc::now().signed_duration_since(v)),
+                        color: match &status {
+                            MonitorStatus::Ok | MonitorStatus::Skipped => COLOR_SUCCESS,
+                            MonitorStatus::Alert | MonitorStatus::Unknown => COLOR_DANGER,
+                            MonitorStatus::Warn => COLOR_WARNING,
+                            MonitorStatus::NoData | MonitorStatus::Ignored => COLOR_OTHER,
+                        }
+                        .to_owned(),
+                        status: status.to_string(),
+                        muted: !value.options.silenced.is_empty(),
+                        ..BadgeOptions::default()
+                    },
+                    status_code: 200,
+                    since,
+                    fresh_for,
+                }
+            }
+        }
+    }
+
+    /// The transitions of the badge, most recent first, with the key from `history_key`
+    pub fn transitions(&self, key: &HistoryKey) -> Vec<Transition> {
+        let _span = info_span!("transition_history.lock").entered();
+        self.history.lock().unwrap().transitions(key)
+    }
+
+    /// Evicts the monitor from the cache, returning the number of cache entries evicted
+    pub async fn evict(&self, account: &str, id: &str) -> usize {
+        self.cache.evict(account, id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use chrono::{Duration, Utc};
+
+    use crate::badge::{COLOR_DANGER, COLOR_OTHER, COLOR_SUCCESS, COLOR_WARNING};
+    use crate::cache::{CachedMonitor, Fetched, MemoryCache, MonitorCache};
+    use crate::datadog::{DatadogError, MonitorState, MonitorStatus, Target};
+    use crate::history::{history_key, TransitionHistory};
+    use crate::service::{BadgeService, CacheTtls, Freshness};
+    use crate::source::FakeSource;
+
+    fn service(source: &Arc<FakeSource>) -> BadgeService {
+        BadgeService::new(
+            source.clone(),
+            Arc::new(MemoryCache::with_lifespan(60)),
+            CacheTtls {
+                default: 15,
+                longest: 60,
+                ..CacheTtls::default()
+            },
+            TransitionHistory::new(10, 100),
+        )
+    }
+
+    fn monitor(id: &str) -> Target {
+        Target::Monitor(id.to_owned())
+    }
+
+    fn state(status: MonitorStatus) -> MonitorState {
+        MonitorState {
+            overall_state: status,
+            overall_state_modified: Some(Utc::now() - Duration::minutes(5)),
+            ..MonitorState::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor_badge() {
+        let source = Arc::new(FakeSource::new());
+        source.set("team", "1", Ok(state(MonitorStatus::Ok)));
+        source.set("team", "2", Err(DatadogError::Upstream(500)));
+        source.set(
+            "unset",
+            "3",
+            Err(DatadogError::Config {
+                account: "unset".to_owned(),
+            }),
+        );
+        let service = service(&source);
+        let query = BTreeMap::new();
+
+        let badge = service
+            .monitor_badge("team", &monitor("1"), &query, Freshness::default())
+            .await;
+        assert_eq!(badge.options.status, "Ok");
+        assert_eq!(badge.options.color, COLOR_SUCCESS);
+        assert!(badge.options.duration.is_some());
+        assert_eq!(badge.status_code, 200);
+        assert_eq!(badge.fresh_for, 15);
+
+        let badge = service
+            .monitor_badge("team", &monitor("2"), &query, Freshness::default())
+            .await;
+        assert_eq!(badge.options.status, "Datadog HTTP/500");
+        assert_eq!(badge.options.color, COLOR_WARNING);
+        assert_eq!(badge.status_code, 500);
+        assert!(badge.since.is_none());
+
+        let badge = service
+            .monitor_badge("team", &monitor("4"), &query, Freshness::default())
+            .await;
+        assert_eq!(badge.options.color, COLOR_OTHER);
+        assert_eq!(badge.status_code, 404);
+
+        let badge = service
+            .monitor_badge("unset", &monitor("3"), &query, Freshness::default())
+            .await;
+        assert_eq!(badge.options.status, "Unconfigured account: unset");
+        assert_eq!(badge.status_code, 404);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_monitor_caches() {
+        let source = Arc::new(FakeSource::new());
+        source.set("team", "1", Ok(state(MonitorStatus::Ok)));
+        source.set(
+            "unset",
+            "2",
+            Err(DatadogError::Config {
+                account: "unset".to_owned(),
+            }),
+        );
+        let service = service(&source);
+        service
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        service
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 1);
+        // the groups are a separate fetch
+        service
+            .fetch_monitor("team", &monitor("1"), true, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 2);
+
+        assert_eq!(service.evict("team", "1").await, 2);
+        service
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 3);
+
+        // unconfigured accounts are not cached
+        service
+            .fetch_monitor("unset", &monitor("2"), false, Freshness::default())
+            .await;
+        service
+            .fetch_monitor("unset", &monitor("2"), false, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 5);
+
+        // SLOs and tag queries have no groups to leave out, so both share one fetch
+        source.set("team", "slo:abc", Ok(state(MonitorStatus::Ok)));
+        let slo = Target::Slo("abc".to_owned());
+        service
+            .fetch_monitor("team", &slo, false, Freshness::default())
+            .await;
+        service
+            .fetch_monitor("team", &slo, true, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_failures_cached_briefly() {
+        let source = Arc::new(FakeSource::new());
+        source.set("team", "1", Err(DatadogError::Upstream(500)));
+        source.set(
+            "busy",
+            "2",
+            Err(DatadogError::RateLimited {
+                retry_after: Some(30),
+            }),
+        );
+        let service = service(&source);
+        let (_, fresh_for) = service
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        assert_eq!(fresh_for, 5);
+        service
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 1);
+        let (_, fresh_for) = service
+            .fetch_monitor("busy", &monitor("2"), false, Freshness::default())
+            .await;
+        assert_eq!(fresh_for, 30);
+
+        // the failure is fetched again once it is a few seconds old, not after the whole TTL
+        let failed = CachedMonitor {
+            result: Fetched::Failed(DatadogError::Upstream(500)),
+            fetched: Utc::now().timestamp() - 6,
+        };
+        service
+            .cache
+            .set(("team".to_owned(), "1".to_owned(), false), failed)
+            .await;
+        service
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_shared() {
+        let source = Arc::new(FakeSource::new());
+        let cache = Arc::new(MemoryCache::with_lifespan(60));
+        // fetched long enough ago to be stale
+        let stale = CachedMonitor {
+            result: Fetched::Monitor(state(MonitorStatus::Ok)),
+            fetched: Utc::now().timestamp() - 30,
+        };
+        cache
+            .set(("team".to_owned(), "1".to_owned(), false), stale)
+            .await;
+        let ttls = CacheTtls {
+            default: 15,
+            longest: 60,
+            ..CacheTtls::default()
+        };
+        let replica = |source: &Arc<FakeSource>| {
+            BadgeService::new(
+                source.clone(),
+                cache.clone(),
+                ttls.clone(),
+                TransitionHistory::new(10, 100),
+            )
+        };
+        let first = replica(&source);
+        source.set(
+            "team",
+            "2",
+            Err(DatadogError::RateLimited {
+                retry_after: Some(30),
+            }),
+        );
+        first
+            .fetch_monitor("team", &monitor("2"), false, Freshness::default())
+            .await;
+        assert_eq!(source.fetches(), 1);
+
+        // another replica sharing the cache does not fetch the account's monitors until the
+        // backoff ends, serving the stale monitor if it has one
+        let other_source = Arc::new(FakeSource::new());
+        let other = replica(&other_source);
+        let (stale, fresh_for) = other
+            .fetch_monitor("team", &monitor("1"), false, Freshness::default())
+            .await;
+        assert!(matches!(stale.result, Fetched::Monitor(_)));
+        assert!(fresh_for > 0 && fresh_for <= 30);
+        let badge = other
+            .monitor_badge(
+                "team",
+                &monitor("3"),
+                &BTreeMap::new(),
+                Freshness::default(),
+            )
+            .await;
+        assert_eq!(badge.status_code, 429);
+        assert_eq!(other_source.fetches(), 0);
+        other
+            .fetch_monitor("other", &monitor("1"), false, Freshness::default())
+            .await;
+        assert_eq!(other_source.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transitions() {
+        let source = Arc::new(FakeSource::new());
+        let service = service(&source);
+        let query = BTreeMap::new();
+        source.set("team", "1", Ok(state(MonitorStatus::Ok)));
+        service
+            .monitor_badge("team", &monitor("1"), &query, Freshness::default())
+            .await;
+        source.set("team", "1", Ok(state(MonitorStatus::Alert)));
+        service.evict("team", "1").await;
+        let badge = service
+            .monitor_badge("team", &monitor("1"), &query, Freshness::default())
+            .await;
+        assert_eq!(badge.options.color, COLOR_DANGER);
+        let transitions = service.transitions(&history_key("team", "1", &query));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].from, MonitorStatus::Ok);
+        assert_eq!(transitions[0].to, MonitorStatus::Alert);
+    }
+}
@@ -0,0 +1,153 @@
+extern crate reqwest;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use futures::future::{self, BoxFuture, FutureExt};
+use reqwest::Client;
+
+use crate::datadog::{
+    get_monitor_details, get_slo_details, get_tagged_monitors, Credentials, DatadogError,
+    MonitorState, Target,
+};
+
+/// Somewhere to get the current state of monitors from
+pub trait MonitorSource: Send + Sync {
+    /// Fetches the state of the target as a monitor, with the state of each of the groups of a
+    /// monitor if `with_groups` is set
+    fn get_monitor<'a>(
+        &'a self,
+        account: &'a str,
+        target: &'a Target,
+        with_groups: bool,
+    ) -> BoxFuture<'a, Result<MonitorState, DatadogError>>;
+}
+
+/// Fetches monitors from the Datadog API with the keys of each account from the environment
+pub struct DatadogSource {
+    client: Client,
+    api_url: String,
+}
+
+impl DatadogSource {
+    /// Creates a source that uses the Datadog API at `api_url`, usually `DEFAULT_API_URL`
+    pub fn new(client: Client, api_url: String) -> DatadogSource {
+        DatadogSource { client, api_url }
+    }
+}
+
+impl MonitorSource for DatadogSource {
+    fn get_monitor<'a>(
+        &'a self,
+        account: &'a str,
+        target: &'a Target,
+        with_groups: bool,
+    ) -> BoxFuture<'a, Result<MonitorState, DatadogError>> {
+        async move {
+            let credentials = Credentials::from_env(account)?;
+            match target {
+                Target::Monitor(id) => {
+                    get_monitor_details(&self.client, &self.api_url, &credentials, id, with_groups)
+                        .await
+                }
+                Target::Slo(id) => {
+                    get_slo_details(&self.client, &self.api_url, &credentials, id).await
+                }
+                Target::Tags(tags) => {
+                    get_tagged_monitors(&self.client, &self.api_url, &credentials, tags).await
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Serves monitors from memory, for tests and tools that have no Datadog account to hand;
+/// monitors that were never set are not found
+#[derive(Default)]
+pub struct FakeSource {
+    monitors: Mutex<HashMap<(String, String), Result<MonitorState, DatadogError>>>,
+    fetches: AtomicUsize,
+}
+
+impl FakeSource {
+    pub fn new() -> FakeSource {
+        FakeSource::default()
+    }
+
+    /// Sets what fetching the target with the ID from `Target::id` returns from now on, whether
+    /// or not groups are requested
+    pub fn set(&self, account: &str, id: &str, result: Result<MonitorState, DatadogError>) {
+        self.monitors
+            .lock()
+            .unwrap()
+            .insert((account.to_owned(), id.to_owned()), result);
+    }
+
+    /// How many times monitors have been fetched
+    pub fn fetches(&self) -> usize {
+        self.fetches.load(Ordering::SeqCst)
+    }
+}
+
+impl MonitorSource for FakeSource {
+    fn get_monitor<'a>(
+        &'a self,
+        account: &'a str,
+        target: &'a Target,
+        _with_groups: bool,
+    ) -> BoxFuture<'a, Result<MonitorState, DatadogError>> {
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+        let result = self
+            .monitors
+            .lock()
+            .unwrap()
+            .get(&(account.to_owned(), target.id()))
+            .cloned()
+            .unwrap_or(Err(DatadogError::NotFound));
+        future::ready(result).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datadog::{DatadogError, MonitorState, MonitorStatus, Target};
+    use crate::source::{FakeSource, MonitorSource};
+
+    #[tokio::test]
+    async fn test_fake_source() {
+        let source = FakeSource::new();
+        let state = MonitorState {
+            overall_state: MonitorStatus::Alert,
+            ..MonitorState::default()
+        };
+        source.set("team", "1", Ok(state.clone()));
+        source.set("team", "2", Err(DatadogError::Timeout));
+        source.set(
+            "team",
+            "slo:abc",
+            Err(DatadogError::RateLimited { retry_after: None }),
+        );
+        let monitor = |id: &str| Target::Monitor(id.to_owned());
+        assert_eq!(
+            source.get_monitor("team", &monitor("1"), false).await,
+            Ok(state)
+        );
+        assert_eq!(
+            source.get_monitor("team", &monitor("2"), true).await,
+            Err(DatadogError::Timeout)
+        );
+        assert_eq!(
+            source.get_monitor("other", &monitor("1"), false).await,
+            Err(DatadogError::NotFound)
+        );
+        assert_eq!(
+            source
+                .get_monitor("team", &Target::Slo("abc".to_owned()), false)
+                .await,
+            Err(DatadogError::RateLimited { retry_after: None })
+        );
+        assert_eq!(source.fetches(), 4);
+    }
+}
@@ -0,0 +1,156 @@
+extern crate opentelemetry;
+extern crate opentelemetry_otlp;
+extern crate opentelemetry_sdk;
+extern crate tracing;
+extern crate tracing_opentelemetry;
+extern crate tracing_subscriber;
+
+use std::collections::HashMap;
+use std::env;
+
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::Context;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// The context of the trace that a `traceparent` header continues, or an empty context to start a
+/// new trace if there is no valid header
+pub fn parent_context(traceparent: Option<&str>) -> Context {
+    let mut carrier = HashMap::new();
+    if let Some(traceparent) = traceparent {
+        carrier.insert("traceparent".to_owned(), traceparent.to_owned());
+    }
+    TraceContextPropagator::new().extract(&carrier)
+}
+
+/// Exports spans in batches to the OpenTelemetry collector at `endpoint` over OTLP/HTTP, naming
+/// the service from `OTEL_SERVICE_NAME`
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    let service_name =
+        env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_owned());
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build())
+}
+
+/// Sends the spans of the `tracing` instrumentation to the tracer provider, leaving out the debug
+/// and trace level spans of the HTTP libraries
+pub fn subscriber(provider: &SdkTracerProvider) -> impl Subscriber + Send + Sync {
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use opentelemetry::trace::TraceContextExt;
+    use tracing::info_span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use crate::telemetry::{parent_context, subscriber, tracer_provider};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_parent_context() {
+        let context = parent_context(Some(TRACEPARENT));
+        let span = context.span();
+        assert!(span.span_context().is_remote());
+        assert_eq!(
+            span.span_context().trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert!(!parent_context(Some("garbage"))
+            .span()
+            .span_context()
+            .is_valid());
+        assert!(!parent_context(None).span().span_context().is_valid());
+    }
+
+    /// Accepts one OTLP/HTTP export and returns its request line and body
+    fn collector() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let (head, body_start) = loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break (
+                        String::from_utf8_lossy(&request[..end]).into_owned(),
+                        end + 4,
+                    );
+                }
+            };
+            let length: usize = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    if name.eq_ignore_ascii_case("content-length") {
+                        value.trim().parse().ok()
+                    } else {
+                        None
+                    }
+                })
+                .unwrap();
+            while request.len() < body_start + length {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let request_line = head.lines().next().unwrap().to_owned();
+            tx.send((request_line, request[body_start..].to_vec()))
+                .unwrap();
+        });
+        (endpoint, rx)
+    }
+
+    #[test]
+    fn test_export() {
+        let (endpoint, exports) = collector();
+        let provider = tracer_provider(&endpoint).unwrap();
+        tracing::subscriber::with_default(subscriber(&provider), || {
+            let request = info_span!("request");
+            request
+                .set_parent(parent_context(Some(TRACEPARENT)))
+                .unwrap();
+            request.in_scope(|| info_span!("badge.to_svg").in_scope(|| {}));
+        });
+        provider.force_flush().unwrap();
+        let (request_line, body) = exports.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"badge.to_svg"));
+        assert!(contains(b"datadog-badges"));
+        // the trace continues the one from the traceparent
+        assert!(contains(&[
+            0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e,
+            0x47, 0x36
+        ]));
+        provider.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+extern crate tokio_rustls;
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::webpki;
+
+/// The schemes tried to check that the private key belongs to the certificate, with how the
+/// certificate verifies their signatures
+static KEY_CHECK_SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+];
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads the certificate chain and the private key, which may be PKCS#8 or PKCS#1 RSA
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certs = pemfile::certs(&mut BufReader::new(fs::File::open(cert_path)?))
+        .map_err(|_| invalid_data("could not parse the certificates"))?;
+    if certs.is_empty() {
+        return Err(invalid_data("no certificates found"));
+    }
+    let key_pem = fs::read(key_path)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
+        .map_err(|_| invalid_data("could not parse the private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut key_pem.as_slice())
+            .map_err(|_| invalid_data("could not parse the private key"))?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| invalid_data("no private key found"))?;
+    let key =
+        sign::any_supported_type(key).map_err(|_| invalid_data("unsupported private key type"))?;
+    let certified_key = CertifiedKey::new(certs, Arc::new(key));
+    check_key_matches(&certified_key)?;
+    Ok(certified_key)
+}
+
+/// Checks that the private key belongs to the first certificate of the chain by signing a message
+/// with the key and verifying the signature with the certificate
+fn check_key_matches(certified_key: &CertifiedKey) -> io::Result<()> {
+    let schemes: Vec<SignatureScheme> = KEY_CHECK_SCHEMES.iter().map(|(s, _)| *s).collect();
+    let signer = certified_key
+        .key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| invalid_data("unsupported private key type"))?;
+    let algorithm = KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .ok_or_else(|| invalid_data("unsupported private key type"))?;
+    let message = b"datadog-badges key check";
+    let signature = signer
+        .sign(message)
+        .map_err(|_| invalid_data("could not sign with the private key"))?;
+    let cert = webpki::EndEntityCert::from(certified_key.cert[0].as_ref())
+        .map_err(|_| invalid_data("could not parse the certificate"))?;
+    cert.verify_signature(algorithm, message, &signature)
+        .map_err(|_| invalid_data("the private key does not match the certificate"))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct LoadedCertificate {
+    cert_modified: Option<SystemTime>,
+    key_modified: Option<SystemTime>,
+    key: CertifiedKey,
+}
+
+/// Serves the certificate and key from a pair of PEM files, reloading them when they change so
+/// that rotated certificates are picked up without a restart
+pub struct ReloadingCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: RwLock<LoadedCertificate>,
+}
+
+impl ReloadingCertificate {
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<ReloadingCertificate> {
+        let loaded = LoadedCertificate {
+            cert_modified: modified(cert_path),
+            key_modified: modified(key_path),
+            key: read_certified_key(cert_path, key_path)?,
+        };
+        Ok(ReloadingCertificate {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// Reloads the certificate if either file has been modified, returning whether it was
+    /// reloaded; the previous certificate stays in use if the new one cannot be read or the key
+    /// does not match it
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let cert_modified = modified(&self.cert_path);
+        let key_modified = modified(&self.key_path);
+        {
+            let loaded = self.loaded.read().unwrap();
+            if loaded.cert_modified == cert_modified && loaded.key_modified == key_modified {
+                return Ok(false);
+            }
+        }
+        let key = read_certified_key(&self.cert_path, &self.key_path)?;
+        *self.loaded.write().unwrap() = LoadedCertificate {
+            cert_modified,
+            key_modified,
+            key,
+        };
+        Ok(true)
+    }
+
+    /// The TLS configuration that serves whichever certificate is currently loaded
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.clone();
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        config
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.loaded.read().unwrap().key.clone())
+    }
+}
+
+/// Where to redirect a plain HTTP request for `path_and_query` on `host` to, given the port that
+/// HTTPS is served on
+pub fn https_location(host: &str, https_port: u16, path_and_query: &str) -> String {
+    // keep the brackets of IPv6 addresses while dropping any port
+    let name = match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    };
+    if https_port == 443 {
+        format!("https://{}{}", name, path_and_query)
+    } else {
+        format!("https://{}:{}{}", name, https_port, path_and_query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use crate::tls::{https_location, ReloadingCertificate};
+
+    const CERT: &str = include_str!("test_data/localhost-cert.pem");
+    const KEY: &str = include_str!("test_data/localhost-key.pem");
+    const OTHER_KEY: &str = include_str!("test_data/other-key.pem");
+
+    #[test]
+    fn test_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_data");
+        assert!(ReloadingCertificate::load(
+            &dir.join("localhost-cert.pem"),
+            &dir.join("localhost-key.pem")
+        )
+        .is_ok());
+        // the halves of the pair the wrong way round
+        assert!(ReloadingCertificate::load(
+            &dir.join("localhost-key.pem"),
+            &dir.join("localhost-cert.pem")
+        )
+        .is_err());
+        // a key that does not belong to the certificate
+        assert!(ReloadingCertificate::load(
+            &dir.join("localhost-cert.pem"),
+            &dir.join("other-key.pem")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location("example.com:8080", 8443, "/badges/x?w=100"),
+            "https://example.com:8443/badges/x?w=100"
+        );
+        assert_eq!(
+            https_location("example.com", 443, "/"),
+            "https://example.com/"
+        );
+        assert_eq!(https_location("[::1]:80", 443, "/"), "https://[::1]/");
+        assert_eq!(https_location("[::1]", 8443, "/"), "https://[::1]:8443/");
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let dir = env::temp_dir().join(format!("datadog-badges-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, CERT).unwrap();
+        fs::write(&key_path, KEY).unwrap();
+        let certificate = ReloadingCertificate::load(&cert_path, &key_path).unwrap();
+        assert!(!certificate.reload_if_changed().unwrap());
+
+        // a broken rotation keeps the old certificate and is retried on the next change
+        fs::write(&cert_path, "garbage").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        fs::File::open(&cert_path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(certificate.reload_if_changed().is_err());
+
+        // as does rotating the key before the certificate
+        fs::write(&cert_path, CERT).unwrap();
+        fs::write(&key_path, OTHER_KEY).unwrap();
+        let later = later + Duration::from_secs(5);
+        for path in &[&cert_path, &key_path] {
+            fs::File::open(path).unwrap().set_modified(later).unwrap();
+        }
+        let error = certificate.reload_if_changed().unwrap_err();
+        assert!(error.to_string().contains("does not match"));
+
+        fs::write(&key_path, KEY).unwrap();
+        fs::File::open(&cert_path)
+            .unwrap()
+            .set_modified(later + Duration::from_secs(5))
+            .unwrap();
+        assert!(certificate.reload_if_changed().unwrap());
+        assert!(!certificate.reload_if_changed().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
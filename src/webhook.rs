@@ -0,0 +1,82 @@
+extern crate serde;
+extern crate serde_json;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+/// The body expected from a Datadog webhook integration, configured with a custom payload of:
+///
+/// ```json
+/// {"account": "example-team", "monitor_id": "$ALERT_ID", "transition": "$ALERT_TRANSITION"}
+/// ```
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct WebhookPayload {
+    /// The Datadog account (subdomain) that the monitor belongs to
+    pub account: String,
+    /// The monitor ID, Datadog renders `$ALERT_ID` as either a string or a number
+    #[serde(deserialize_with = "string_or_number")]
+    pub monitor_id: String,
+    /// The transition that triggered the webhook, for logging only
+    #[serde(default)]
+    pub transition: Option<String>,
+}
+
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or number, found {}",
+            other
+        ))),
+    }
+}
+
+pub fn parse_payload(body: &[u8]) -> Result<WebhookPayload, serde_json::Error> {
+    serde_json::from_slice(body)
+}
+
+/// Compares the supplied secret with the expected one without short-circuiting on the first
+/// difference, so that the response time does not leak how much of the secret was correct
+pub fn secret_matches(expected: &str, actual: &str) -> bool {
+    let expected = expected.as_bytes();
+    let actual = actual.as_bytes();
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webhook::{parse_payload, secret_matches};
+
+    #[test]
+    fn test_parse_payload() {
+        let payload = parse_payload(
+            br#"{"account":"example-team","monitor_id":"12345","transition":"Triggered"}"#,
+        )
+        .unwrap();
+        assert_eq!(payload.account, "example-team");
+        assert_eq!(payload.monitor_id, "12345");
+        assert_eq!(payload.transition.as_deref(), Some("Triggered"));
+        let payload = parse_payload(br#"{"account":"example-team","monitor_id":12345}"#).unwrap();
+        assert_eq!(payload.monitor_id, "12345");
+        assert_eq!(payload.transition, None);
+        assert!(parse_payload(br#"{"account":"example-team","monitor_id":[]}"#).is_err());
+        assert!(parse_payload(br#"{"monitor_id":12345}"#).is_err());
+    }
+
+    #[test]
+    fn test_secret_matches() {
+        assert!(secret_matches("s3cr3t", "s3cr3t"));
+        assert!(!secret_matches("s3cr3t", "s3cr3"));
+        assert!(!secret_matches("s3cr3t", "s3cr3T"));
+        assert!(!secret_matches("s3cr3t", ""));
+    }
+}